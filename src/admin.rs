@@ -0,0 +1,321 @@
+use crate::blocklist::{Blocklist, ListKind};
+use crate::message;
+use crate::resolver::Strategy;
+use crate::server::{self, Forwarder, Upstream};
+use crate::stats::ProviderStats;
+use crate::{Protocol, Provider};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use hyper::header::{ALLOW, AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN, WWW_AUTHENTICATE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use trust_dns_proto::rr::RecordType as DnsRecordType;
+
+/// Control endpoint for a running `serve`, reachable only from this machine:
+///
+/// - `GET /stats`: latency and success history per provider and protocol
+/// - `GET /cache`, `DELETE /cache`: cached answers, and flushing them
+/// - `POST /blocklist/reload`: re-read the block and allow lists
+/// - `GET /upstream`, `PUT /upstream`: the provider (or `race`, `fastest`) and
+///   protocols queries are forwarded to
+///
+/// Binding to loopback keeps other machines out but not web pages in the local
+/// browser, so requests must also name the listen address as `Host` (defeating DNS
+/// rebinding) and carry no foreign `Origin` (defeating cross-site requests).
+pub struct AdminServer {
+    forwarder: Arc<Forwarder>,
+    token: Option<String>,
+}
+
+impl AdminServer {
+    pub fn new(forwarder: Arc<Forwarder>) -> Self {
+        Self {
+            forwarder,
+            token: None,
+        }
+    }
+
+    /// Also require `Authorization: Bearer <token>` on every request
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Serve plain HTTP on `listen`, which must be a loopback address
+    pub async fn run(self, listen: SocketAddr) -> Result<()> {
+        if !listen.ip().is_loopback() {
+            anyhow::bail!(
+                "The admin API only listens on loopback addresses, not {}",
+                listen
+            );
+        }
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} (admin)", listen))?;
+
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = server::accept(&listener, "Admin").await;
+
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let server = Arc::clone(&server);
+                    async move {
+                        let response = match server.refusal(&request, listen) {
+                            Some(refusal) => refusal,
+                            None => handle(&server.forwarder, request).await,
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+                if let Err(e) = Http::new().serve_connection(stream, service).await {
+                    let what = format!("Admin connection from {} failed", peer);
+                    server.forwarder.log_error(&what, &e.into());
+                }
+            });
+        }
+    }
+
+    /// The response turning `request` away if a browser could have been tricked into
+    /// sending it, or it lacks the token when one is set
+    fn refusal(&self, request: &Request<Body>, listen: SocketAddr) -> Option<Response<Body>> {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap_or(""))
+        };
+        let ours = |host: &str| {
+            host == listen.to_string() || host == format!("localhost:{}", listen.port())
+        };
+
+        if !header(HOST).is_some_and(ours) {
+            return Some(error(
+                StatusCode::FORBIDDEN,
+                "Host does not match the admin address",
+            ));
+        }
+        if let Some(origin) = header(ORIGIN) {
+            let local = origin.strip_prefix("http://").is_some_and(ours);
+            if !local {
+                return Some(error(
+                    StatusCode::FORBIDDEN,
+                    "Cross-origin requests are not allowed",
+                ));
+            }
+        }
+        if let Some(token) = &self.token {
+            let presented = header(AUTHORIZATION).and_then(|value| value.strip_prefix("Bearer "));
+            if presented != Some(token.as_str()) {
+                let mut response = error(StatusCode::UNAUTHORIZED, "Missing or wrong admin token");
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+                return Some(response);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Serialize)]
+struct StatsView {
+    provider: Provider,
+    protocol: Protocol,
+    #[serde(flatten)]
+    stats: ProviderStats,
+}
+
+#[derive(Serialize)]
+struct CacheView {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    /// Only set when the cache is kept per provider
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_for: Option<Provider>,
+    provider: Provider,
+    protocol: Protocol,
+    ttl_left: u64,
+    negative: bool,
+    stale: bool,
+    records: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ListView {
+    path: String,
+    kind: &'static str,
+    entries: usize,
+    hits: u64,
+}
+
+#[derive(Serialize)]
+struct UpstreamView {
+    strategy: String,
+    protocols: Vec<Protocol>,
+}
+
+/// Body of `PUT /upstream`; fields left out keep their current value
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamChange {
+    provider: Option<String>,
+    protocols: Option<Vec<String>>,
+}
+
+async fn handle(forwarder: &Forwarder, request: Request<Body>) -> Response<Body> {
+    let method = request.method().clone();
+    match (request.uri().path(), method) {
+        ("/stats", Method::GET) => {
+            let stats: Vec<StatsView> = forwarder
+                .resolver()
+                .stats()
+                .snapshot()
+                .into_iter()
+                .map(|(provider, protocol, stats)| StatsView {
+                    provider,
+                    protocol,
+                    stats,
+                })
+                .collect();
+            reply(StatusCode::OK, &stats)
+        }
+        ("/cache", Method::GET) => {
+            let Some(cache) = forwarder.resolver().cache() else {
+                return reply(StatusCode::OK, &Vec::<CacheView>::new());
+            };
+            let entries: Vec<CacheView> = cache
+                .entries()
+                .into_iter()
+                .map(|(key, cached)| CacheView {
+                    name: key.name,
+                    record_type: DnsRecordType::from(key.record_type).to_string(),
+                    cached_for: key.provider,
+                    records: if cached.negative {
+                        Vec::new()
+                    } else {
+                        message::parse_records(&cached.response).unwrap_or_default()
+                    },
+                    provider: cached.provider,
+                    protocol: cached.protocol,
+                    ttl_left: cached.ttl_left.as_secs(),
+                    negative: cached.negative,
+                    stale: cached.stale,
+                })
+                .collect();
+            reply(StatusCode::OK, &entries)
+        }
+        ("/cache", Method::DELETE) => {
+            let flushed = forwarder
+                .resolver()
+                .cache()
+                .map_or(0, |cache| cache.clear());
+            reply(StatusCode::OK, &json!({ "flushed": flushed }))
+        }
+        ("/blocklist/reload", Method::POST) => match forwarder.reload_blocklist() {
+            Ok(Some(blocklist)) => reply(StatusCode::OK, &lists(&blocklist)),
+            Ok(None) => error(StatusCode::CONFLICT, "No block lists are configured"),
+            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", e)),
+        },
+        ("/upstream", Method::GET) => reply(StatusCode::OK, &upstream_view(&forwarder.upstream())),
+        ("/upstream", Method::PUT) => {
+            let body = match hyper::body::to_bytes(request.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+            };
+            let change: UpstreamChange = match serde_json::from_slice(&body) {
+                Ok(change) => change,
+                Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+            };
+            match apply(forwarder.upstream(), change) {
+                Ok(upstream) => {
+                    forwarder.set_upstream(upstream.clone());
+                    reply(StatusCode::OK, &upstream_view(&upstream))
+                }
+                Err(e) => error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+            }
+        }
+        (path, _) => {
+            let allow = match path {
+                "/stats" => "GET",
+                "/cache" => "GET, DELETE",
+                "/blocklist/reload" => "POST",
+                "/upstream" => "GET, PUT",
+                _ => return error(StatusCode::NOT_FOUND, "No such endpoint"),
+            };
+            let mut response = error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+            response.headers_mut().insert(ALLOW, allow.parse().unwrap());
+            response
+        }
+    }
+}
+
+/// The upstream after `change`: `race`, `fastest` or a built-in provider, and protocols
+fn apply(mut upstream: Upstream, change: UpstreamChange) -> Result<Upstream> {
+    if let Some(provider) = change.provider {
+        upstream.strategy = match provider.as_str() {
+            "race" => Strategy::Race,
+            "fastest" => Strategy::Fastest,
+            name => Strategy::Provider(
+                Provider::from_str(name, true)
+                    .map_err(|_| anyhow::anyhow!("Unknown provider '{}'", name))?,
+            ),
+        };
+    }
+    if let Some(protocols) = change.protocols {
+        if protocols.is_empty() {
+            anyhow::bail!("Needs at least one protocol");
+        }
+        upstream.protocols = protocols
+            .iter()
+            .map(|p| {
+                Protocol::from_str(p, true).map_err(|_| anyhow::anyhow!("Unknown protocol '{}'", p))
+            })
+            .collect::<Result<_>>()?;
+    }
+    Ok(upstream)
+}
+
+fn upstream_view(upstream: &Upstream) -> UpstreamView {
+    UpstreamView {
+        strategy: upstream.strategy.to_string(),
+        protocols: upstream.protocols.clone(),
+    }
+}
+
+fn lists(blocklist: &Blocklist) -> Vec<ListView> {
+    blocklist
+        .lists()
+        .iter()
+        .map(|list| ListView {
+            path: list.path.display().to_string(),
+            kind: match list.kind {
+                ListKind::Block => "blocklist",
+                ListKind::Allow => "allowlist",
+            },
+            entries: list.entries,
+            hits: list.hits(),
+        })
+        .collect()
+}
+
+fn reply<T: Serialize>(code: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec_pretty(body).unwrap()))
+        .unwrap()
+}
+
+fn error(code: StatusCode, message: &str) -> Response<Body> {
+    reply(code, &json!({ "error": message }))
+}
@@ -0,0 +1,377 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Names hosts files map to themselves rather than block
+const HOSTS_LOCAL: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+/// How blocked names are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BlockPolicy {
+    /// The name does not exist
+    Nxdomain,
+    /// 0.0.0.0 / :: for address queries, an empty answer for anything else
+    Null,
+    /// The server refuses to answer
+    Refused,
+}
+
+/// Whether a list blocks names or exempts them from blocking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListKind {
+    Block,
+    Allow,
+}
+
+/// One domain from a list line
+#[derive(Debug)]
+struct Entry {
+    domain: String,
+    /// Also covers every name under the domain (adblock `||domain^`)
+    subdomains: bool,
+    /// An adblock exception (`@@||domain^`) inside a blocklist
+    exception: bool,
+}
+
+/// Parse one list line in hosts (`0.0.0.0 a.com b.com`), plain-domain (`a.com`) or
+/// adblock (`||a.com^`, `@@||a.com^`) format; comments and unsupported rules yield nothing
+fn parse_line(line: &str) -> Vec<Entry> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return Vec::new();
+    }
+
+    let (rule, exception) = match line.strip_prefix("@@") {
+        Some(rule) => (rule, true),
+        None => (line, false),
+    };
+    if let Some(rule) = rule.strip_prefix("||") {
+        // Rules with options ($third-party, ...) or paths cannot be applied to DNS
+        return rule
+            .strip_suffix('^')
+            .and_then(domain)
+            .map(|domain| Entry {
+                domain,
+                subdomains: true,
+                exception,
+            })
+            .into_iter()
+            .collect();
+    }
+    if exception {
+        return Vec::new();
+    }
+
+    let mut tokens = line.split_whitespace();
+    let first = tokens.next().unwrap_or("");
+    let names: Vec<&str> = if first.parse::<IpAddr>().is_ok() {
+        tokens.collect()
+    } else if tokens.next().is_none() {
+        vec![first]
+    } else {
+        Vec::new()
+    };
+
+    names
+        .into_iter()
+        .filter_map(domain)
+        .filter(|domain| !HOSTS_LOCAL.contains(&domain.as_str()))
+        .map(|domain| Entry {
+            domain,
+            subdomains: false,
+            exception: false,
+        })
+        .collect()
+}
+
+/// Normalise a list token into a domain, or `None` if it is not one
+fn domain(token: &str) -> Option<String> {
+    let domain = token.trim_end_matches('.').to_ascii_lowercase();
+    let valid = !domain.is_empty()
+        && domain
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+        && !domain.split('.').any(str::is_empty);
+    valid.then_some(domain)
+}
+
+/// A list entry as stored in the trie: which list it came from and what it covers
+#[derive(Debug, Clone, Copy)]
+struct Mark {
+    list: usize,
+    subdomains: bool,
+}
+
+/// Domains keyed by their labels from the top-level domain down, so a lookup walks a
+/// name once and finds every listed suffix of it on the way
+#[derive(Default)]
+struct SuffixTrie {
+    children: HashMap<Box<str>, SuffixTrie>,
+    mark: Option<Mark>,
+}
+
+impl SuffixTrie {
+    fn insert(&mut self, domain: &str, mark: Mark) {
+        let mut node = self;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        // A subdomain entry is broader than an exact one for the same name
+        match &mut node.mark {
+            Some(existing) => existing.subdomains |= mark.subdomains,
+            None => node.mark = Some(mark),
+        }
+    }
+
+    /// The list covering `name`, preferring the broadest listed suffix
+    fn find(&self, name: &str) -> Option<usize> {
+        let labels: Vec<&str> = name.rsplit('.').collect();
+        let mut node = self;
+        for (depth, label) in labels.iter().enumerate() {
+            node = node.children.get(*label)?;
+            if let Some(mark) = node.mark {
+                if mark.subdomains || depth + 1 == labels.len() {
+                    return Some(mark.list);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A loaded list file and how often it decided a query
+pub struct List {
+    pub path: PathBuf,
+    pub kind: ListKind,
+    pub entries: usize,
+    hits: AtomicU64,
+}
+
+impl List {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+/// Block and allow lists merged into suffix tries; allowed names are never blocked
+pub struct Blocklist {
+    policy: BlockPolicy,
+    lists: Vec<List>,
+    block: SuffixTrie,
+    allow: SuffixTrie,
+}
+
+impl Blocklist {
+    /// Load every list; allowlist entries and adblock exceptions take precedence
+    pub fn load(
+        blocklists: &[PathBuf],
+        allowlists: &[PathBuf],
+        policy: BlockPolicy,
+    ) -> Result<Self> {
+        let mut blocklist = Self {
+            policy,
+            lists: Vec::new(),
+            block: SuffixTrie::default(),
+            allow: SuffixTrie::default(),
+        };
+        for path in blocklists {
+            blocklist.add(path, ListKind::Block)?;
+        }
+        for path in allowlists {
+            blocklist.add(path, ListKind::Allow)?;
+        }
+        Ok(blocklist)
+    }
+
+    /// Load the same lists again, e.g. after they changed on disk; hit counts start over
+    pub fn reload(&self) -> Result<Self> {
+        let paths = |kind| -> Vec<PathBuf> {
+            self.lists
+                .iter()
+                .filter(|list| list.kind == kind)
+                .map(|list| list.path.clone())
+                .collect()
+        };
+        Self::load(
+            &paths(ListKind::Block),
+            &paths(ListKind::Allow),
+            self.policy,
+        )
+    }
+
+    fn add(&mut self, path: &Path, kind: ListKind) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read list {}", path.display()))?;
+
+        let list = self.lists.len();
+        let mut entries = 0;
+        for entry in text.lines().flat_map(parse_line) {
+            let mark = Mark {
+                list,
+                subdomains: entry.subdomains,
+            };
+            if kind == ListKind::Allow || entry.exception {
+                self.allow.insert(&entry.domain, mark);
+            } else {
+                self.block.insert(&entry.domain, mark);
+            }
+            entries += 1;
+        }
+
+        self.lists.push(List {
+            path: path.to_path_buf(),
+            kind,
+            entries,
+            hits: AtomicU64::new(0),
+        });
+        Ok(())
+    }
+
+    /// The list blocking `hostname`, counting a hit for whichever list decided
+    pub fn check(&self, hostname: &str) -> Option<&List> {
+        let name = hostname.trim_end_matches('.').to_ascii_lowercase();
+        let blocked = self.block.find(&name)?;
+
+        if let Some(allowed) = self.allow.find(&name) {
+            self.lists[allowed].hits.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let list = &self.lists[blocked];
+        list.hits.fetch_add(1, Ordering::Relaxed);
+        Some(list)
+    }
+
+    pub fn policy(&self) -> BlockPolicy {
+        self.policy
+    }
+
+    pub fn lists(&self) -> &[List] {
+        &self.lists
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains(line: &str) -> Vec<(String, bool, bool)> {
+        parse_line(line)
+            .into_iter()
+            .map(|entry| (entry.domain, entry.subdomains, entry.exception))
+            .collect()
+    }
+
+    fn list_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "sdr-blocklist-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn adblock_rules_and_exceptions() {
+        assert_eq!(
+            domains("||Ads.Example.com^"),
+            [("ads.example.com".to_string(), true, false)]
+        );
+        assert_eq!(
+            domains("@@||ok.example.com^"),
+            [("ok.example.com".to_string(), true, true)]
+        );
+        // Options and paths only make sense to a browser
+        assert!(domains("||ads.example.com^$third-party").is_empty());
+        assert!(domains("||example.com/banner^").is_empty());
+        assert!(domains("@@ok.example.com").is_empty());
+        assert!(domains("! Title: some list").is_empty());
+        assert!(domains("[Adblock Plus 2.0]").is_empty());
+    }
+
+    #[test]
+    fn hosts_lines_skip_local_names() {
+        assert_eq!(
+            domains("0.0.0.0 localhost ads.example.com tracker.example.com # two"),
+            [
+                ("ads.example.com".to_string(), false, false),
+                ("tracker.example.com".to_string(), false, false),
+            ]
+        );
+        assert!(domains("127.0.0.1 localhost.localdomain").is_empty());
+        assert!(domains("::1 ip6-localhost ip6-loopback").is_empty());
+        assert_eq!(
+            domains("ads.example.com."),
+            [("ads.example.com".to_string(), false, false)]
+        );
+        assert!(domains("not a domain").is_empty());
+    }
+
+    #[test]
+    fn subdomain_entry_wins_over_exact_one() {
+        let exact = Mark {
+            list: 0,
+            subdomains: false,
+        };
+        let subdomains = Mark {
+            list: 1,
+            subdomains: true,
+        };
+
+        let mut trie = SuffixTrie::default();
+        trie.insert("example.com", exact);
+        assert_eq!(trie.find("example.com"), Some(0));
+        assert_eq!(trie.find("www.example.com"), None);
+        assert_eq!(trie.find("com"), None);
+
+        trie.insert("example.com", subdomains);
+        assert_eq!(trie.find("www.example.com"), Some(0));
+
+        // The broadest listed suffix decides
+        trie.insert("ads.example.com", subdomains);
+        assert_eq!(trie.find("x.ads.example.com"), Some(0));
+    }
+
+    #[test]
+    fn allowlist_and_exceptions_override_blocks() {
+        let block = [list_file(
+            "block",
+            "||example.com^\n@@||ok.example.com^\n0.0.0.0 tracker.net\n",
+        )];
+        let allow = [list_file("allow", "allowed.example.com\n")];
+        let blocklist = Blocklist::load(&block, &allow, BlockPolicy::Null);
+        for path in block.iter().chain(&allow) {
+            std::fs::remove_file(path).unwrap();
+        }
+        let blocklist = blocklist.unwrap();
+
+        let blocked = |name| blocklist.check(name).map(|list| list.kind);
+        assert_eq!(blocked("ads.example.com"), Some(ListKind::Block));
+        assert_eq!(blocked("Tracker.NET."), Some(ListKind::Block));
+        assert_eq!(blocked("www.tracker.net"), None);
+        assert_eq!(blocked("ok.example.com"), None);
+        assert_eq!(blocked("cdn.ok.example.com"), None);
+        assert_eq!(blocked("allowed.example.com"), None);
+        // A plain allowlist entry only exempts the name itself
+        assert_eq!(blocked("x.allowed.example.com"), Some(ListKind::Block));
+
+        // Exceptions count as hits of the list they are written in
+        let hits: Vec<u64> = blocklist.lists().iter().map(List::hits).collect();
+        assert_eq!(hits, [5, 1]);
+    }
+}
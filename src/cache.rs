@@ -0,0 +1,413 @@
+use crate::message;
+use crate::{Protocol, Provider};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// DNS class IN; the only class this resolver queries
+const CLASS_IN: u16 = 1;
+
+/// Expired entries are swept out after this many inserts
+const PURGE_EVERY: usize = 1024;
+
+/// Expired entries stay in the cache file at least this long, so runs that serve
+/// stale answers can still use what runs without it wrote
+const FILE_RETENTION: Duration = Duration::from_secs(86_400);
+
+/// TTL given to every record of a stale answer (RFC 8767 section 4)
+const STALE_ANSWER_TTL: u32 = 30;
+
+/// How long answers may stay in the cache
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    /// Answers with a shorter TTL are kept this long anyway
+    pub min_ttl: Duration,
+    /// Answers with a longer TTL are dropped after this long
+    pub max_ttl: Duration,
+    /// Keep separate entries per provider instead of sharing answers between them
+    pub per_provider: bool,
+    /// Keep expired answers this much longer, to be served when every upstream
+    /// fails (RFC 8767); zero disables serving stale answers
+    pub stale_window: Duration,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            min_ttl: Duration::ZERO,
+            max_ttl: Duration::from_secs(86_400),
+            per_provider: false,
+            stale_window: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    /// Only set when the policy keeps answers per provider
+    pub provider: Option<Provider>,
+}
+
+/// A cached upstream response and where it came from
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    /// Wire-format response exactly as the provider sent it
+    pub response: Vec<u8>,
+    pub provider: Provider,
+    pub protocol: Protocol,
+    pub ttl_left: Duration,
+    /// NXDOMAIN or NODATA, cached per RFC 2308
+    pub negative: bool,
+    /// Past its TTL; only served because no upstream could be reached
+    pub stale: bool,
+}
+
+impl CachedResponse {
+    /// The response as it should be relayed: record TTLs cut down to what is left
+    /// of the entry, or to `STALE_ANSWER_TTL` once it is stale
+    pub fn relayed(&self) -> Result<Vec<u8>> {
+        if self.stale {
+            return message::rewrite_ttls(&self.response, |_| STALE_ANSWER_TTL);
+        }
+        let left = self.ttl_left.as_secs().min(u32::MAX as u64) as u32;
+        message::rewrite_ttls(&self.response, |ttl| ttl.min(left))
+    }
+}
+
+struct Entry {
+    response: Vec<u8>,
+    provider: Provider,
+    protocol: Protocol,
+    expires: Instant,
+    negative: bool,
+}
+
+impl Entry {
+    fn cached(&self, now: Instant) -> CachedResponse {
+        CachedResponse {
+            response: self.response.clone(),
+            provider: self.provider.clone(),
+            protocol: self.protocol,
+            ttl_left: self.expires.saturating_duration_since(now),
+            negative: self.negative,
+            stale: self.expires <= now,
+        }
+    }
+}
+
+/// One cache entry as stored in the cache file
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    answered_by: Provider,
+    protocol: Protocol,
+    /// Unix timestamp (seconds) at which the entry expires; may be in the past
+    /// for entries kept to be served stale
+    expires: u64,
+    negative: bool,
+    /// Base64 of the wire-format response
+    response: String,
+}
+
+/// In-memory cache of upstream responses, expired by record TTL
+pub struct DnsCache {
+    policy: CachePolicy,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+    inserts: AtomicUsize,
+}
+
+impl DnsCache {
+    pub fn new(policy: CachePolicy) -> Self {
+        Self {
+            policy,
+            entries: Mutex::new(HashMap::new()),
+            inserts: AtomicUsize::new(0),
+        }
+    }
+
+    /// Cache key for a query; names compare case-insensitively and without the root dot
+    pub fn key(&self, hostname: &str, record_type: u16, provider: &Provider) -> CacheKey {
+        CacheKey {
+            name: hostname.trim_end_matches('.').to_ascii_lowercase(),
+            record_type,
+            class: CLASS_IN,
+            provider: self.policy.per_provider.then(|| provider.clone()),
+        }
+    }
+
+    /// The cached response for `key`, unless it has expired
+    pub fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        match entries.get(key) {
+            Some(entry) if entry.expires > now => Some(entry.cached(now)),
+            Some(entry) if !self.retained(entry, now) => {
+                entries.remove(key);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Any answer for the query still within its stale window, most recent first,
+    /// to fall back on when resolution failed
+    pub fn stale(&self, hostname: &str, record_type: u16) -> Option<CachedResponse> {
+        let keys: Vec<CacheKey> = if self.policy.per_provider {
+            Provider::all()
+                .iter()
+                .map(|provider| self.key(hostname, record_type, provider))
+                .collect()
+        } else {
+            vec![self.key(hostname, record_type, &Provider::Cloudflare)]
+        };
+
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        keys.iter()
+            .filter_map(|key| entries.get(key))
+            .filter(|entry| self.retained(entry, now))
+            .max_by_key(|entry| entry.expires)
+            .map(|entry| entry.cached(now))
+    }
+
+    /// Whether an entry is fresh or still within the stale window
+    fn retained(&self, entry: &Entry, now: Instant) -> bool {
+        entry
+            .expires
+            .checked_add(self.policy.stale_window)
+            .map_or(true, |until| until > now)
+    }
+
+    /// Store an upstream response under `key` for its (clamped) TTL.
+    ///
+    /// Returns how long it will be kept, or `None` if the response is not cacheable.
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        response: &[u8],
+        provider: &Provider,
+        protocol: Protocol,
+    ) -> Option<Duration> {
+        let ttl = message::cache_ttl(response)?.clamp(
+            self.policy.min_ttl,
+            self.policy.max_ttl.max(self.policy.min_ttl),
+        );
+        if ttl.is_zero() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        let inserts = self.inserts.fetch_add(1, Ordering::Relaxed) + 1;
+        if inserts % PURGE_EVERY == 0 {
+            entries.retain(|_, entry| self.retained(entry, now));
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                response: response.to_vec(),
+                provider: provider.clone(),
+                protocol,
+                expires: now + ttl,
+                negative: message::is_negative(response),
+            },
+        );
+        Some(ttl)
+    }
+
+    /// Every entry that is fresh or within its stale window, soonest expiry first
+    pub fn entries(&self) -> Vec<(CacheKey, CachedResponse)> {
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let mut list: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| self.retained(entry, now))
+            .map(|(key, entry)| (entry.expires, key.clone(), entry.cached(now)))
+            .collect();
+        list.sort_by_key(|(expires, _, _)| *expires);
+        list.into_iter()
+            .map(|(_, key, cached)| (key, cached))
+            .collect()
+    }
+
+    /// Number of entries held in memory, expired ones included until they are evicted
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Drop every entry held in memory, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    /// Load entries saved by earlier runs that are fresh or within the stale window;
+    /// a missing file is not an error
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let _lock = lock(path, false)?;
+        let stored = read_entries(path)?;
+
+        let now = Instant::now();
+        let unix = unix_now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut loaded = 0;
+        let window = self.policy.stale_window.as_secs();
+        for stored in stored {
+            if stored.expires.saturating_add(window) <= unix {
+                continue;
+            }
+            let Ok(response) = STANDARD.decode(&stored.response) else {
+                continue;
+            };
+            let expires = if stored.expires >= unix {
+                now + Duration::from_secs(stored.expires - unix)
+            } else {
+                now.checked_sub(Duration::from_secs(unix - stored.expires))
+                    .unwrap_or(now)
+            };
+            entries.insert(
+                stored.key,
+                Entry {
+                    response,
+                    provider: stored.answered_by,
+                    protocol: stored.protocol,
+                    expires,
+                    negative: stored.negative,
+                },
+            );
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Merge this run's entries into the cache file.
+    ///
+    /// The file is re-read under an exclusive lock and replaced atomically, so
+    /// parallel invocations neither corrupt it nor drop each other's answers.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let _lock = lock(path, true)?;
+        let unix = unix_now();
+        let now = Instant::now();
+        let window = self.policy.stale_window.max(FILE_RETENTION).as_secs();
+
+        // A corrupt file is simply replaced
+        let mut merged: HashMap<CacheKey, DiskEntry> = read_entries(path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|stored| stored.expires.saturating_add(window) > unix)
+            .map(|stored| (stored.key.clone(), stored))
+            .collect();
+
+        for (key, entry) in self.entries.lock().unwrap().iter() {
+            if !self.retained(entry, now) {
+                continue;
+            }
+            let expires = if entry.expires >= now {
+                unix + (entry.expires - now).as_secs()
+            } else {
+                unix.saturating_sub((now - entry.expires).as_secs())
+            };
+            if merged
+                .get(key)
+                .is_some_and(|stored| stored.expires >= expires)
+            {
+                continue;
+            }
+            merged.insert(
+                key.clone(),
+                DiskEntry {
+                    key: key.clone(),
+                    answered_by: entry.provider.clone(),
+                    protocol: entry.protocol,
+                    expires,
+                    negative: entry.negative,
+                    response: STANDARD.encode(&entry.response),
+                },
+            );
+        }
+
+        let entries: Vec<DiskEntry> = merged.into_values().collect();
+        let data = serde_json::to_string(&entries)?;
+        let tmp = sibling(path, "tmp");
+        std::fs::write(&tmp, data).context("Failed to write cache file")?;
+        std::fs::rename(&tmp, path).context("Failed to replace cache file")
+    }
+
+    /// Remove every entry from the cache file, returning how many there were
+    pub fn flush(path: &Path) -> Result<usize> {
+        let _lock = lock(path, true)?;
+        let count = read_entries(path).map(|entries| entries.len()).unwrap_or(0);
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(count),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e).context("Failed to remove cache file"),
+        }
+    }
+}
+
+/// `<path>.<suffix>`, next to the cache file
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Take the lock guarding the cache file; released when the returned file is dropped.
+///
+/// A separate lock file is used because the cache file itself is replaced by rename.
+fn lock(path: &Path, exclusive: bool) -> Result<File> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).context("Failed to create cache directory")?;
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(sibling(path, "lock"))
+        .context("Failed to open cache lock file")?;
+
+    // Spelled out: newer Rust has an inherent `File::lock_shared` that would shadow fs2's
+    if exclusive {
+        FileExt::lock_exclusive(&file)
+    } else {
+        FileExt::lock_shared(&file)
+    }
+    .context("Failed to lock cache file")?;
+
+    Ok(file)
+}
+
+fn read_entries(path: &Path) -> Result<Vec<DiskEntry>> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read cache file"),
+    };
+    serde_json::from_str(&data).context("Failed to parse cache file")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,97 @@
+use crate::resolver::{DnsResolver, Resolved, Strategy};
+use crate::{Protocol, RecordType};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::net::IpAddr;
+
+/// What a validator may use to run lookups of its own
+pub struct CandidateContext<'a> {
+    pub resolver: &'a DnsResolver,
+    pub protocols: &'a [Protocol],
+    pub strategy: &'a Strategy,
+}
+
+/// Decides whether a candidate domain that resolved counts as live.
+///
+/// Returning an error rejects the candidate; the message is the reason shown.
+pub trait Validator: Send + Sync {
+    fn validate<'a>(
+        &'a self,
+        context: &'a CandidateContext<'a>,
+        candidate: &'a str,
+        answer: &'a Resolved<Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Rejects answers pointing at sinkhole addresses: unspecified and loopback
+/// addresses always, plus any explicitly listed ones
+pub struct RejectSinkholes {
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Validator for RejectSinkholes {
+    fn validate<'a>(
+        &'a self,
+        _context: &'a CandidateContext<'a>,
+        _candidate: &'a str,
+        answer: &'a Resolved<Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        let sinkhole = answer
+            .data
+            .iter()
+            .filter_map(|record| record.parse::<IpAddr>().ok())
+            .find(|ip| ip.is_unspecified() || ip.is_loopback() || self.addresses.contains(ip));
+
+        let result = match sinkhole {
+            Some(ip) => Err(anyhow::anyhow!("Resolves to sinkhole address {}", ip)),
+            None => Ok(()),
+        };
+        async move { result }.boxed()
+    }
+}
+
+/// Requires a TXT record containing `marker` at the candidate, or at
+/// `<prefix>.<candidate>` when a prefix is set
+pub struct RequireTxt {
+    pub prefix: Option<String>,
+    pub marker: String,
+}
+
+impl Validator for RequireTxt {
+    fn validate<'a>(
+        &'a self,
+        context: &'a CandidateContext<'a>,
+        candidate: &'a str,
+        _answer: &'a Resolved<Vec<String>>,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let name = match &self.prefix {
+                Some(prefix) => format!("{}.{}", prefix, candidate),
+                None => candidate.to_string(),
+            };
+
+            let txt = context
+                .resolver
+                .resolve_strategy(&name, context.protocols, context.strategy, &RecordType::TXT)
+                .await
+                .map_err(|e| anyhow::anyhow!("No TXT marker at {}: {}", name, e))?;
+
+            if txt.data.iter().any(|record| record.contains(&self.marker)) {
+                Ok(())
+            } else {
+                anyhow::bail!("TXT records at {} lack marker '{}'", name, self.marker)
+            }
+        }
+        .boxed()
+    }
+}
+
+/// The first candidate that resolved and passed every validator
+#[derive(Debug)]
+pub struct LiveCandidate {
+    pub domain: String,
+    pub resolved: Resolved<Vec<String>>,
+    /// Candidates ahead of the winner in the list, with why they were skipped
+    pub rejected: Vec<(String, String)>,
+}
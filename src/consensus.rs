@@ -0,0 +1,85 @@
+use crate::resolver::Resolved;
+use crate::Provider;
+
+/// Comparison of the answers several providers gave for the same query
+#[derive(Debug, Clone)]
+pub struct Consensus {
+    /// Normalized answer set reported by each provider that responded
+    pub answers: Vec<(Provider, Vec<String>)>,
+    /// Providers that failed, with their error
+    pub failures: Vec<(Provider, String)>,
+    /// Answer set shared by a strict majority of the responses, if any
+    pub majority: Option<Vec<String>>,
+    /// Providers whose answer matches the majority
+    pub agreeing: Vec<Provider>,
+    /// Providers whose answer differs from the majority (all of them when there is none)
+    pub deviating: Vec<Provider>,
+}
+
+impl Consensus {
+    /// Compare the successful responses, ignoring record order and duplicates
+    pub fn evaluate(
+        responses: Vec<Resolved<Vec<String>>>,
+        failures: Vec<(Provider, String)>,
+    ) -> Self {
+        let answers: Vec<(Provider, Vec<String>)> = responses
+            .into_iter()
+            .map(|resolved| (resolved.provider, normalize(resolved.data)))
+            .collect();
+
+        // Tally identical answer sets, keeping first-seen order for stable output
+        let mut tally: Vec<(&Vec<String>, usize)> = Vec::new();
+        for (_, set) in &answers {
+            match tally.iter_mut().find(|(seen, _)| *seen == set) {
+                Some((_, count)) => *count += 1,
+                None => tally.push((set, 1)),
+            }
+        }
+
+        let majority = tally
+            .iter()
+            .max_by_key(|(_, count)| *count)
+            .filter(|(_, count)| count * 2 > answers.len())
+            .map(|(set, _)| (*set).clone());
+
+        let (agreeing, deviating) = match &majority {
+            Some(set) => {
+                let (agree, deviate): (Vec<_>, Vec<_>) =
+                    answers.iter().partition(|(_, answer)| answer == set);
+                (
+                    agree.into_iter().map(|(p, _)| p.clone()).collect(),
+                    deviate.into_iter().map(|(p, _)| p.clone()).collect(),
+                )
+            }
+            None => (Vec::new(), answers.iter().map(|(p, _)| p.clone()).collect()),
+        };
+
+        Self {
+            answers,
+            failures,
+            majority,
+            agreeing,
+            deviating,
+        }
+    }
+
+    /// Every provider that answered returned the same set
+    pub fn unanimous(&self) -> bool {
+        self.majority.is_some() && self.deviating.is_empty()
+    }
+
+    /// The answer set a deviating provider returned
+    pub fn answer_of(&self, provider: &Provider) -> Option<&[String]> {
+        self.answers
+            .iter()
+            .find(|(p, _)| p == provider)
+            .map(|(_, answer)| answer.as_slice())
+    }
+}
+
+/// Sort and deduplicate records so equal sets compare equal
+fn normalize(mut records: Vec<String>) -> Vec<String> {
+    records.sort();
+    records.dedup();
+    records
+}
@@ -1,31 +1,33 @@
+use crate::error::{DnsError, Phase};
+use crate::metrics::Metrics;
 use crate::providers::DnsProviderConfig;
-use crate::RecordType;
+use crate::timeout::{guard, Timeouts};
+use crate::{Protocol, RecordType};
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use bytes::Buf;
-use colored::*;
 use h3::client::SendRequest;
 use h3_quinn::OpenStreams;
 use quinn::{ClientConfig, Endpoint};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Instant;
-use trust_dns_proto::op::{Message, MessageType, OpCode, Query};
-use trust_dns_proto::rr::{Name, RecordType as DnsRecordType};
-use trust_dns_proto::serialize::binary::BinEncodable;
+use tracing::debug;
 
 pub struct Doh3Resolver {
     client_config: ClientConfig,
+    timeouts: Timeouts,
+    metrics: Arc<Metrics>,
 }
 
 impl Doh3Resolver {
-    pub fn new() -> Self {
+    pub fn new(timeouts: Timeouts, metrics: Arc<Metrics>) -> Self {
         let mut root_store = rustls::RootCertStore::empty();
         root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
             rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject.as_ref(),
-                ta.spki.as_ref(),
-                ta.name_constraints.as_deref(),
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
             )
         }));
 
@@ -38,141 +40,76 @@ impl Doh3Resolver {
 
         let client_config = ClientConfig::new(Arc::new(tls_config));
 
-        Self { client_config }
-    }
-
-    pub async fn resolve(
-        &self,
-        hostname: &str,
-        provider: &DnsProviderConfig,
-        record_type: u16,
-        verbose: bool,
-    ) -> Result<Vec<String>> {
-        let query = self.build_dns_query(hostname, record_type)?;
-        let response = self
-            .send_doh3_request(provider, &query, hostname, record_type, verbose)
-            .await?;
-
-        let result = self.parse_dns_response(&response);
-
-        if verbose {
-            match &result {
-                Ok(records) => {
-                    eprintln!(
-                        "{}",
-                        format!(
-                            "  [verbose] [DoH3] ✓ Parsed {} record(s) for '{}'",
-                            records.len(),
-                            hostname
-                        )
-                        .dimmed()
-                    );
-                    for record in records {
-                        eprintln!("{}", format!("  [verbose] [DoH3]   → {}", record).dimmed());
-                    }
-                }
-                Err(e) => {
-                    eprintln!(
-                        "{}",
-                        format!("  [verbose] [DoH3] ✗ Failed to parse response: {}", e).red()
-                    );
-                }
-            }
+        Self {
+            client_config,
+            timeouts,
+            metrics,
         }
-
-        result
-    }
-
-    pub async fn resolve_raw(
-        &self,
-        hostname: &str,
-        provider: &DnsProviderConfig,
-        record_type: u16,
-        verbose: bool,
-    ) -> Result<Vec<u8>> {
-        let query = self.build_dns_query(hostname, record_type)?;
-        let response = self
-            .send_doh3_request(provider, &query, hostname, record_type, verbose)
-            .await?;
-        self.extract_raw_rdata(&response)
     }
 
-    async fn send_doh3_request(
+    /// Send a wire-format query and return the wire-format response
+    pub async fn exchange(
         &self,
         provider: &DnsProviderConfig,
         dns_query: &[u8],
         hostname: &str,
         record_type: u16,
-        verbose: bool,
     ) -> Result<Vec<u8>> {
         let server_addr = self.resolve_server_addr(provider)?;
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoH3] → Connecting to {} ({}) for '{}' ({} query)",
-                    provider.name,
-                    server_addr,
-                    hostname,
-                    RecordType::from_code(record_type)
-                )
-                .dimmed()
-            );
-        }
+        debug!(
+            server = %server_addr,
+            "Connecting to {} for {} ({} query)",
+            provider.name,
+            hostname,
+            RecordType::from_code(record_type)
+        );
 
         let start = Instant::now();
 
         let mut endpoint = Endpoint::client("0.0.0.0:0".parse::<SocketAddr>()?)?;
         endpoint.set_default_client_config(self.client_config.clone());
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!("  [verbose] [DoH3]   QUIC endpoint created, initiating connection...")
-                    .dimmed()
-            );
-        }
+        debug!("QUIC endpoint created, initiating connection");
 
-        let connection = endpoint
-            .connect(server_addr, provider.doh3_hostname)?
-            .await
-            .context("Failed to establish QUIC connection")?;
+        let connection = guard(
+            Phase::Handshake,
+            self.timeouts.setup(),
+            endpoint.connect(server_addr, &provider.doh3_hostname)?,
+        )
+        .await?
+        .context("Failed to establish QUIC connection")?;
 
         let quic_elapsed = start.elapsed();
+        self.metrics
+            .phase(Protocol::Doh3, Phase::Handshake, quic_elapsed);
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoH3]   QUIC connection established in {:.2?}",
-                    quic_elapsed
-                )
-                .dimmed()
-            );
-        }
+        debug!(elapsed = ?quic_elapsed, "QUIC connection established");
 
         let quinn_conn = h3_quinn::Connection::new(connection);
-        let (mut driver, send_request) = h3::client::new(quinn_conn)
-            .await
-            .context("Failed to create HTTP/3 connection")?;
+        let (mut driver, send_request) = guard(
+            Phase::Handshake,
+            self.timeouts.handshake,
+            h3::client::new(quinn_conn),
+        )
+        .await?
+        .context("Failed to create HTTP/3 connection")?;
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!("  [verbose] [DoH3]   HTTP/3 session established").dimmed()
-            );
-        }
+        debug!("HTTP/3 session established");
 
         let drive_fut = async move {
             std::future::poll_fn(|cx| driver.poll_close(cx)).await?;
             Ok::<(), h3::Error>(())
         };
 
-        let request_fut = self.send_request(send_request, provider, dns_query, hostname, verbose);
+        let request_fut = guard(
+            Phase::Response,
+            self.timeouts.response,
+            self.send_request(send_request, provider, dns_query, hostname),
+        );
 
         let result = tokio::select! {
-            result = request_fut => result,
+            result = request_fut => result?,
             result = drive_fut => {
                 result?;
                 Err(anyhow::anyhow!("Connection closed unexpectedly"))
@@ -180,18 +117,12 @@ impl Doh3Resolver {
         };
 
         let total_elapsed = start.elapsed();
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoH3]   Total request time: {:.2?}",
-                    total_elapsed
-                )
-                .dimmed()
-            );
+        if result.is_ok() {
+            self.metrics.total(Protocol::Doh3, total_elapsed);
         }
 
+        debug!(total = ?total_elapsed, "HTTP/3 exchange finished");
+
         endpoint.wait_idle().await;
         result
     }
@@ -202,27 +133,11 @@ impl Doh3Resolver {
         provider: &DnsProviderConfig,
         dns_query: &[u8],
         hostname: &str,
-        verbose: bool,
     ) -> Result<Vec<u8>> {
         let encoded = URL_SAFE_NO_PAD.encode(dns_query);
         let uri = format!("{}?dns={}", provider.doh3_url, encoded);
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!("  [verbose] [DoH3] → Sending HTTP/3 GET request").dimmed()
-            );
-            eprintln!("{}", format!("  [verbose] [DoH3]   URI: {}", uri).dimmed());
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoH3]   Query size: {} bytes (base64: {} chars)",
-                    dns_query.len(),
-                    encoded.len()
-                )
-                .dimmed()
-            );
-        }
+        debug!(%uri, bytes = dns_query.len(), "Sending HTTP/3 GET request");
 
         let request = http::Request::builder()
             .method("GET")
@@ -247,30 +162,21 @@ impl Doh3Resolver {
 
         let status = response.status();
         let response_elapsed = request_start.elapsed();
+        self.metrics
+            .phase(Protocol::Doh3, Phase::Response, response_elapsed);
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoH3] ← Received HTTP/3 response (HTTP {}) in {:.2?}",
-                    status, response_elapsed
-                )
-                .dimmed()
-            );
-        }
+        debug!(
+            elapsed = ?response_elapsed,
+            status = status.as_u16(),
+            "Received HTTP/3 response"
+        );
 
         if !status.is_success() {
-            if verbose {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "  [verbose] [DoH3] ✗ Request failed with HTTP status: {}",
-                        status
-                    )
-                    .red()
-                );
+            return Err(DnsError::HttpStatus {
+                transport: "HTTP/3",
+                code: status.as_u16(),
             }
-            anyhow::bail!("HTTP/3 request failed with status: {}", status);
+            .into());
         }
 
         let mut body = Vec::new();
@@ -282,17 +188,7 @@ impl Doh3Resolver {
             body.extend_from_slice(chunk.chunk());
         }
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoH3]   Response body: {} bytes for '{}'",
-                    body.len(),
-                    hostname
-                )
-                .dimmed()
-            );
-        }
+        debug!(bytes = body.len(), "Response body read for {}", hostname);
 
         Ok(body)
     }
@@ -305,55 +201,4 @@ impl Doh3Resolver {
             .next()
             .context("No address found for server")
     }
-
-    fn build_dns_query(&self, hostname: &str, record_type: u16) -> Result<Vec<u8>> {
-        let name = Name::from_ascii(hostname).context("Invalid hostname")?;
-        let record_type = DnsRecordType::from(record_type);
-
-        let mut message = Message::new();
-        message.set_id(rand::random());
-        message.set_message_type(MessageType::Query);
-        message.set_op_code(OpCode::Query);
-        message.set_recursion_desired(true);
-
-        let query = Query::query(name, record_type);
-        message.add_query(query);
-
-        let bytes = message.to_bytes().context("Failed to encode DNS query")?;
-        Ok(bytes)
-    }
-
-    fn parse_dns_response(&self, data: &[u8]) -> Result<Vec<String>> {
-        let message = Message::from_vec(data).context("Failed to parse DNS response")?;
-
-        let mut results = Vec::new();
-
-        for answer in message.answers() {
-            let rdata = answer.data().map(|d| format!("{}", d));
-            if let Some(data) = rdata {
-                results.push(data);
-            }
-        }
-
-        if results.is_empty() {
-            anyhow::bail!("No records found");
-        }
-
-        Ok(results)
-    }
-
-    fn extract_raw_rdata(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let message = Message::from_vec(data).context("Failed to parse DNS response")?;
-
-        for answer in message.answers() {
-            if let Some(rdata) = answer.data() {
-                use trust_dns_proto::serialize::binary::BinEncodable;
-                if let Ok(bytes) = rdata.to_bytes() {
-                    return Ok(bytes);
-                }
-            }
-        }
-
-        anyhow::bail!("No RDATA found in response")
-    }
 }
@@ -0,0 +1,187 @@
+use crate::message;
+use crate::server::{self, Forwarder, Transport};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::body::HttpBody;
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Clients that have not finished the TLS handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// DNS messages never exceed this, so larger POST bodies are rejected unread
+const MAX_MESSAGE: usize = u16::MAX as usize;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DNS-over-HTTPS (RFC 8484) listener serving `/dns-query` to clients on the network
+pub struct DohServer {
+    forwarder: Arc<Forwarder>,
+    acceptor: TlsAcceptor,
+}
+
+impl DohServer {
+    /// Serve with the PEM certificate chain and private key at `cert` and `key`
+    pub fn new(forwarder: Arc<Forwarder>, cert: &Path, key: &Path) -> Result<Self> {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .context("Invalid certificate or key")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            forwarder,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Accept HTTPS connections on `listen` until the listener fails
+    pub async fn run(self, listen: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} (HTTPS)", listen))?;
+
+        loop {
+            let (stream, peer) = server::accept(&listener, "HTTPS").await;
+
+            let acceptor = self.acceptor.clone();
+            let forwarder = Arc::clone(&self.forwarder);
+            tokio::spawn(async move {
+                let tls =
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => tls,
+                        Ok(Err(e)) => {
+                            let what = format!("TLS handshake with {} failed", peer);
+                            forwarder.log_error(&what, &e.into());
+                            return;
+                        }
+                        Err(_) => return,
+                    };
+
+                let service = service_fn(|request| {
+                    let forwarder = Arc::clone(&forwarder);
+                    async move { Ok::<_, Infallible>(handle(&forwarder, request, peer).await) }
+                });
+                if let Err(e) = Http::new().serve_connection(tls, service).await {
+                    let what = format!("HTTPS connection from {} failed", peer);
+                    forwarder.log_error(&what, &e.into());
+                }
+            });
+        }
+    }
+}
+
+/// Answer one HTTP request: a DNS query by GET (`?dns=`) or POST to `/dns-query`
+async fn handle(forwarder: &Forwarder, request: Request<Body>, peer: SocketAddr) -> Response<Body> {
+    if request.uri().path() != "/dns-query" {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *request.method() {
+        Method::GET => {
+            let param = request
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("dns="));
+            let Some(param) = param else {
+                return status(StatusCode::BAD_REQUEST);
+            };
+            match URL_SAFE_NO_PAD.decode(param.trim_end_matches('=')) {
+                Ok(query) => query,
+                Err(_) => return status(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            let content_type = request.headers().get(CONTENT_TYPE);
+            if content_type.map_or(true, |value| value != DNS_MESSAGE) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match read_body(request.into_body()).await {
+                Some(query) => query,
+                None => return status(StatusCode::PAYLOAD_TOO_LARGE),
+            }
+        }
+        _ => {
+            let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(ALLOW, "GET, POST".parse().unwrap());
+            return response;
+        }
+    };
+
+    let Some(answer) = forwarder.answer(&query, peer, Transport::Https).await else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, DNS_MESSAGE);
+    // Let HTTP caches keep the answer no longer than its records (RFC 8484 section 5.1);
+    // answers from our own cache already carry only their remaining TTL, and stale
+    // ones 30 seconds, so max-age never outlives what the resolver would serve
+    if let Some(ttl) = message::cache_ttl(&answer) {
+        response = response.header(CACHE_CONTROL, format!("max-age={}", ttl.as_secs()));
+    }
+    response.body(Body::from(answer)).unwrap()
+}
+
+/// The request body, or `None` if it is larger than any DNS message
+async fn read_body(mut body: Body) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.ok()?);
+        if data.len() > MAX_MESSAGE {
+            return None;
+        }
+    }
+    Some(data)
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open certificate {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificate {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open private key {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key {}", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
@@ -1,29 +1,31 @@
+use crate::error::Phase;
+use crate::metrics::Metrics;
 use crate::providers::DnsProviderConfig;
-use crate::RecordType;
+use crate::timeout::{guard, Timeouts};
+use crate::{Protocol, RecordType};
 use anyhow::{Context, Result};
-use colored::*;
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
 use tokio_rustls::TlsConnector;
-use trust_dns_proto::op::{Message, MessageType, OpCode, Query};
-use trust_dns_proto::rr::{Name, RecordType as DnsRecordType};
-use trust_dns_proto::serialize::binary::BinEncodable;
+use tracing::debug;
 
 pub struct DotResolver {
     tls_config: Arc<ClientConfig>,
+    timeouts: Timeouts,
+    metrics: Arc<Metrics>,
 }
 
 impl DotResolver {
-    pub fn new() -> Self {
+    pub fn new(timeouts: Timeouts, metrics: Arc<Metrics>) -> Self {
         let mut root_store = RootCertStore::empty();
         root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
             OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject.as_ref(),
-                ta.spki.as_ref(),
-                ta.name_constraints.as_deref(),
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
             )
         }));
 
@@ -34,295 +36,110 @@ impl DotResolver {
 
         Self {
             tls_config: Arc::new(config),
+            timeouts,
+            metrics,
         }
     }
 
-    pub async fn resolve(
+    /// Send a wire-format query and return the wire-format response
+    pub async fn exchange(
         &self,
-        hostname: &str,
         provider: &DnsProviderConfig,
+        query: &[u8],
+        hostname: &str,
         record_type: u16,
-        verbose: bool,
-    ) -> Result<Vec<String>> {
+    ) -> Result<Vec<u8>> {
         let addr = format!("{}:{}", provider.dot_host, provider.dot_port);
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT] → Connecting to {} ({}) for '{}' ({} query)",
-                    provider.name,
-                    addr,
-                    hostname,
-                    RecordType::from_code(record_type)
-                )
-                .dimmed()
-            );
-        }
+        debug!(
+            server = %addr,
+            "Connecting to {} for {} ({} query)",
+            provider.name,
+            hostname,
+            RecordType::from_code(record_type)
+        );
 
         let start = Instant::now();
 
-        let stream = TcpStream::connect(&addr)
-            .await
-            .context("Failed to connect to DoT server")?;
+        let stream = guard(
+            Phase::Connect,
+            self.timeouts.connect,
+            TcpStream::connect(&addr),
+        )
+        .await?
+        .context("Failed to connect to DoT server")?;
 
         let connect_elapsed = start.elapsed();
+        self.metrics
+            .phase(Protocol::Dot, Phase::Connect, connect_elapsed);
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT]   TCP connection established in {:.2?}",
-                    connect_elapsed
-                )
-                .dimmed()
-            );
-        }
+        debug!(elapsed = ?connect_elapsed, "TCP connection established");
 
-        let server_name = ServerName::try_from(provider.dot_hostname)
+        let server_name = ServerName::try_from(provider.dot_hostname.as_str())
             .map_err(|_| anyhow::anyhow!("Invalid server name"))?;
 
         let connector = TlsConnector::from(self.tls_config.clone());
 
         let tls_start = Instant::now();
-        let mut tls_stream = connector
-            .connect(server_name, stream)
-            .await
-            .context("TLS handshake failed")?;
+        let mut tls_stream = guard(
+            Phase::Handshake,
+            self.timeouts.handshake,
+            connector.connect(server_name, stream),
+        )
+        .await?
+        .context("TLS handshake failed")?;
 
         let tls_elapsed = tls_start.elapsed();
+        self.metrics
+            .phase(Protocol::Dot, Phase::Handshake, tls_elapsed);
 
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT]   TLS handshake completed in {:.2?}",
-                    tls_elapsed
-                )
-                .dimmed()
-            );
-        }
-
-        let query = self.build_dns_query(hostname, record_type)?;
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT] → Sending DNS query ({} bytes)",
-                    query.len()
-                )
-                .dimmed()
-            );
-        }
-
-        let len = (query.len() as u16).to_be_bytes();
-        tls_stream.write_all(&len).await?;
-        tls_stream.write_all(&query).await?;
-        tls_stream.flush().await?;
+        debug!(elapsed = ?tls_elapsed, "TLS handshake completed");
+        debug!(bytes = query.len(), "Sending DNS query");
 
         let query_start = Instant::now();
 
-        let mut len_buf = [0u8; 2];
-        tls_stream.read_exact(&mut len_buf).await?;
-        let response_len = u16::from_be_bytes(len_buf) as usize;
-
-        let mut response = vec![0u8; response_len];
-        tls_stream.read_exact(&mut response).await?;
+        let response = guard(
+            Phase::Response,
+            self.timeouts.response,
+            Self::send(&mut tls_stream, query),
+        )
+        .await??;
+        let response_len = response.len();
 
         let query_elapsed = query_start.elapsed();
         let total_elapsed = start.elapsed();
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT] ← Received response from {} ({} bytes) in {:.2?}",
-                    provider.name, response_len, query_elapsed
-                )
-                .dimmed()
-            );
-            eprintln!(
-                "{}",
-                format!("  [verbose] [DoT]   Total time: {:.2?}", total_elapsed).dimmed()
-            );
-        }
-
-        let result = self.parse_dns_response(&response);
-
-        if verbose {
-            match &result {
-                Ok(records) => {
-                    eprintln!(
-                        "{}",
-                        format!(
-                            "  [verbose] [DoT] ✓ Parsed {} record(s) for '{}'",
-                            records.len(),
-                            hostname
-                        )
-                        .dimmed()
-                    );
-                    for record in records {
-                        eprintln!("{}", format!("  [verbose] [DoT]   → {}", record).dimmed());
-                    }
-                }
-                Err(e) => {
-                    eprintln!(
-                        "{}",
-                        format!("  [verbose] [DoT] ✗ Failed to parse response: {}", e).red()
-                    );
-                }
-            }
-        }
-
-        result
+        self.metrics
+            .phase(Protocol::Dot, Phase::Response, query_elapsed);
+        self.metrics.total(Protocol::Dot, total_elapsed);
+
+        debug!(
+            bytes = response_len,
+            elapsed = ?query_elapsed,
+            total = ?total_elapsed,
+            "Received response from {}",
+            provider.name
+        );
+
+        Ok(response)
     }
 
-    pub async fn resolve_raw(
-        &self,
-        hostname: &str,
-        provider: &DnsProviderConfig,
-        record_type: u16,
-        verbose: bool,
-    ) -> Result<Vec<u8>> {
-        let addr = format!("{}:{}", provider.dot_host, provider.dot_port);
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT] → Connecting to {} ({}) for '{}' ({} query, raw)",
-                    provider.name,
-                    addr,
-                    hostname,
-                    RecordType::from_code(record_type)
-                )
-                .dimmed()
-            );
-        }
-
-        let start = Instant::now();
-
-        let stream = TcpStream::connect(&addr)
-            .await
-            .context("Failed to connect to DoT server")?;
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT]   TCP connection established in {:.2?}",
-                    start.elapsed()
-                )
-                .dimmed()
-            );
-        }
-
-        let server_name = ServerName::try_from(provider.dot_hostname)
-            .map_err(|_| anyhow::anyhow!("Invalid server name"))?;
-
-        let connector = TlsConnector::from(self.tls_config.clone());
-        let mut tls_stream = connector
-            .connect(server_name, stream)
-            .await
-            .context("TLS handshake failed")?;
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!("  [verbose] [DoT]   TLS handshake completed").dimmed()
-            );
-        }
-
-        let query = self.build_dns_query(hostname, record_type)?;
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT] → Sending DNS query ({} bytes)",
-                    query.len()
-                )
-                .dimmed()
-            );
-        }
-
+    /// Write a length-prefixed query and read back the length-prefixed answer
+    async fn send<S>(stream: &mut S, query: &[u8]) -> Result<Vec<u8>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let len = (query.len() as u16).to_be_bytes();
-        tls_stream.write_all(&len).await?;
-        tls_stream.write_all(&query).await?;
-        tls_stream.flush().await?;
+        stream.write_all(&len).await?;
+        stream.write_all(query).await?;
+        stream.flush().await?;
 
         let mut len_buf = [0u8; 2];
-        tls_stream.read_exact(&mut len_buf).await?;
+        stream.read_exact(&mut len_buf).await?;
         let response_len = u16::from_be_bytes(len_buf) as usize;
 
         let mut response = vec![0u8; response_len];
-        tls_stream.read_exact(&mut response).await?;
-
-        if verbose {
-            eprintln!(
-                "{}",
-                format!(
-                    "  [verbose] [DoT] ← Received response from {} ({} bytes) in {:.2?}",
-                    provider.name,
-                    response_len,
-                    start.elapsed()
-                )
-                .dimmed()
-            );
-        }
-
-        self.extract_raw_rdata(&response)
-    }
-
-    fn build_dns_query(&self, hostname: &str, record_type: u16) -> Result<Vec<u8>> {
-        let name = Name::from_ascii(hostname).context("Invalid hostname")?;
-        let record_type = DnsRecordType::from(record_type);
-
-        let mut message = Message::new();
-        message.set_id(rand::random());
-        message.set_message_type(MessageType::Query);
-        message.set_op_code(OpCode::Query);
-        message.set_recursion_desired(true);
-
-        let query = Query::query(name, record_type);
-        message.add_query(query);
-
-        let bytes = message.to_bytes().context("Failed to encode DNS query")?;
-        Ok(bytes)
-    }
-
-    fn parse_dns_response(&self, data: &[u8]) -> Result<Vec<String>> {
-        let message = Message::from_vec(data).context("Failed to parse DNS response")?;
-
-        let mut results = Vec::new();
-
-        for answer in message.answers() {
-            let rdata = answer.data().map(|d| format!("{}", d));
-            if let Some(data) = rdata {
-                results.push(data);
-            }
-        }
-
-        if results.is_empty() {
-            anyhow::bail!("No records found");
-        }
-
-        Ok(results)
-    }
-
-    fn extract_raw_rdata(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let message = Message::from_vec(data).context("Failed to parse DNS response")?;
-
-        for answer in message.answers() {
-            if let Some(rdata) = answer.data() {
-                use trust_dns_proto::serialize::binary::BinEncodable;
-                if let Ok(bytes) = rdata.to_bytes() {
-                    return Ok(bytes);
-                }
-            }
-        }
+        stream.read_exact(&mut response).await?;
 
-        anyhow::bail!("No RDATA found in response")
+        Ok(response)
     }
 }
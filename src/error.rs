@@ -0,0 +1,178 @@
+use clap::ValueEnum;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Phase of a single upstream exchange, used to label timeouts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// TCP connection setup
+    Connect,
+    /// TLS or QUIC handshake (and HTTP/3 session setup)
+    Handshake,
+    /// Sending the query and reading the answer
+    Response,
+    /// Connection setup, handshake and query together, for transports that do not
+    /// expose them separately (DoH, where reqwest pools and sets up connections)
+    Exchange,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Connect => write!(f, "connect"),
+            Phase::Handshake => write!(f, "handshake"),
+            Phase::Response => write!(f, "response"),
+            Phase::Exchange => write!(f, "exchange"),
+        }
+    }
+}
+
+/// Typed errors that callers need to tell apart from generic failures
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("Timed out in {phase} phase after {after:.2?}")]
+    Timeout { phase: Phase, after: Duration },
+
+    #[error("Batch deadline of {0:.2?} exceeded")]
+    Deadline(Duration),
+
+    #[error("{transport} request failed with status: {code}")]
+    HttpStatus { transport: &'static str, code: u16 },
+
+    #[error("Refused by routing rule")]
+    Refused,
+}
+
+impl DnsError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, DnsError::Timeout { .. } | DnsError::Deadline(_))
+    }
+}
+
+/// One failure handed to every caller of a coalesced query.
+///
+/// Displays and chains exactly like the original error, which `classify` and
+/// `is_timeout` look through.
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<anyhow::Error>);
+
+impl SharedError {
+    pub fn new(err: anyhow::Error) -> Self {
+        Self(Arc::new(err))
+    }
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl StdError for SharedError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Every cause in an error's context chain, including those of shared errors
+fn causes(err: &anyhow::Error) -> Vec<&(dyn StdError + 'static)> {
+    let mut causes = Vec::new();
+    for cause in err.chain() {
+        if let Some(shared) = cause.downcast_ref::<SharedError>() {
+            causes.extend(self::causes(&shared.0));
+            break;
+        }
+        causes.push(cause);
+    }
+    causes
+}
+
+/// Check whether an error (or anything in its context chain) is a routing refusal
+pub fn is_refused(err: &anyhow::Error) -> bool {
+    causes(err)
+        .into_iter()
+        .any(|cause| matches!(cause.downcast_ref::<DnsError>(), Some(DnsError::Refused)))
+}
+
+/// Check whether an error (or anything in its context chain) is a timeout
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    causes(err)
+        .into_iter()
+        .filter_map(|cause| cause.downcast_ref::<DnsError>())
+        .any(DnsError::is_timeout)
+}
+
+/// Coarse failure categories used to decide whether a lookup is worth retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorClass {
+    /// A connect, handshake or response timeout
+    Timeout,
+    /// Connection refused, reset or closed mid-exchange
+    Connection,
+    /// TLS or QUIC handshake failure
+    Handshake,
+    /// HTTP 5xx from a DoH/DoH3 server
+    ServerError,
+    /// Anything else (bad hostname, unparsable answer, empty answer, ...)
+    Other,
+}
+
+impl ErrorClass {
+    /// Failures of the transport itself, as opposed to a bad query or answer
+    pub fn is_transport(&self) -> bool {
+        !matches!(self, ErrorClass::Other)
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorClass::Timeout => write!(f, "timeout"),
+            ErrorClass::Connection => write!(f, "connection"),
+            ErrorClass::Handshake => write!(f, "handshake"),
+            ErrorClass::ServerError => write!(f, "server-error"),
+            ErrorClass::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Work out which class an error belongs to by inspecting its context chain
+pub fn classify(err: &anyhow::Error) -> ErrorClass {
+    for cause in causes(err) {
+        if let Some(e) = cause.downcast_ref::<DnsError>() {
+            return match e {
+                DnsError::Timeout { .. } => ErrorClass::Timeout,
+                DnsError::HttpStatus { code, .. } if *code >= 500 => ErrorClass::ServerError,
+                _ => ErrorClass::Other,
+            };
+        }
+        if cause.downcast_ref::<quinn::ConnectionError>().is_some() {
+            return ErrorClass::Handshake;
+        }
+        if cause.downcast_ref::<h3::Error>().is_some() {
+            return ErrorClass::Connection;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_timeout() {
+                return ErrorClass::Timeout;
+            }
+            if e.is_connect() || e.is_request() {
+                return ErrorClass::Connection;
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            // rustls reports handshake failures as InvalidData
+            return match e.kind() {
+                ErrorKind::InvalidData => ErrorClass::Handshake,
+                ErrorKind::TimedOut => ErrorClass::Timeout,
+                _ => ErrorClass::Connection,
+            };
+        }
+    }
+
+    ErrorClass::Other
+}
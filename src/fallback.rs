@@ -0,0 +1,41 @@
+use crate::{Protocol, Provider};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Ordered list of protocols to try against a provider (e.g. DoH3 → DoH → DoT)
+///
+/// One chain is shared by every lookup of a batch. Once a protocol works for a
+/// provider, later lookups to that provider start from it instead of walking
+/// the protocols that already failed.
+pub struct ProtocolChain {
+    protocols: Vec<Protocol>,
+    learned: Mutex<HashMap<Provider, usize>>,
+}
+
+impl ProtocolChain {
+    pub fn new(protocols: &[Protocol]) -> Self {
+        Self {
+            protocols: protocols.to_vec(),
+            learned: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Protocols to try for `provider`, starting from the one that last worked
+    pub fn candidates(&self, provider: &Provider) -> &[Protocol] {
+        let start = self
+            .learned
+            .lock()
+            .unwrap()
+            .get(provider)
+            .copied()
+            .unwrap_or(0);
+        &self.protocols[start..]
+    }
+
+    /// Remember that `protocol` worked for `provider`
+    pub fn learn(&self, provider: &Provider, protocol: Protocol) {
+        if let Some(index) = self.protocols.iter().position(|p| *p == protocol) {
+            self.learned.lock().unwrap().insert(provider.clone(), index);
+        }
+    }
+}
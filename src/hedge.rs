@@ -0,0 +1,43 @@
+use crate::stats::StatsTracker;
+use crate::{Protocol, Provider};
+use std::time::Duration;
+
+/// When to send the hedge (second) request
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    /// Provider asked second if the preferred one is slow; without one (or if it
+    /// is the preferred provider itself) the best-scoring other provider is asked
+    pub secondary: Option<Provider>,
+    /// Wait this long for the preferred provider before hedging
+    pub delay: Duration,
+    /// Instead of `delay`, wait for this latency percentile (0-100) of the
+    /// preferred provider once enough samples have been observed
+    pub percentile: Option<f64>,
+}
+
+impl HedgePolicy {
+    /// Delay before hedging requests to `provider` over `protocol`
+    pub fn delay_for(
+        &self,
+        provider: &Provider,
+        protocol: Protocol,
+        stats: &StatsTracker,
+    ) -> Duration {
+        self.percentile
+            .and_then(|p| stats.percentile(provider, protocol, p))
+            .unwrap_or(self.delay)
+    }
+
+    /// Provider to hedge requests to `primary` with, never `primary` itself
+    pub fn secondary_for(
+        &self,
+        primary: &Provider,
+        protocol: Protocol,
+        stats: &StatsTracker,
+    ) -> Provider {
+        match &self.secondary {
+            Some(secondary) if secondary != primary => secondary.clone(),
+            _ => stats.select_other(protocol, primary),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::RecordType;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use futures::stream::{self, Stream};
+use std::path::Path;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
+
+/// One hostname read from an input file, with the record type to query
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub hostname: String,
+    pub record_type: RecordType,
+}
+
+/// Parse one input line of the form `hostname [TYPE]`.
+///
+/// Blank lines and `#` comments (whole-line or trailing) yield `None`; lines
+/// without a type use `default`.
+pub fn parse_line(line: &str, default: &RecordType) -> Result<Option<(String, RecordType)>> {
+    let line = match line.split_once('#') {
+        Some((before, _)) => before,
+        None => line,
+    };
+
+    let mut fields = line
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|f| !f.is_empty());
+
+    let hostname = match fields.next() {
+        Some(hostname) => hostname.to_string(),
+        None => return Ok(None),
+    };
+
+    let record_type = match fields.next() {
+        Some(name) => RecordType::from_str(name, true)
+            .map_err(|_| anyhow::anyhow!("Unknown record type '{}'", name))?,
+        None => default.clone(),
+    };
+
+    if let Some(extra) = fields.next() {
+        anyhow::bail!("Unexpected field '{}' after record type", extra);
+    }
+
+    Ok(Some((hostname, record_type)))
+}
+
+type LineReader = Lines<Box<dyn AsyncBufRead + Unpin + Send>>;
+
+/// Read queries from `path` (or stdin for `-`) one line at a time, so even very
+/// large lists are never held in memory. Malformed lines are yielded as errors
+/// and reading continues; a read error ends the stream.
+pub async fn read_queries(
+    path: &Path,
+    default: RecordType,
+) -> Result<impl Stream<Item = Result<Query>>> {
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = if path == Path::new("-") {
+        Box::new(BufReader::new(tokio::io::stdin()))
+    } else {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Failed to open input file {}", path.display()))?;
+        Box::new(BufReader::new(file))
+    };
+
+    let state: (Option<LineReader>, usize) = (Some(reader.lines()), 0);
+
+    Ok(stream::unfold(state, move |(lines, mut number)| {
+        let default = default.clone();
+        async move {
+            let mut lines = lines?;
+            loop {
+                number += 1;
+                match lines.next_line().await {
+                    Ok(Some(line)) => match parse_line(&line, &default) {
+                        Ok(Some((hostname, record_type))) => {
+                            let query = Query {
+                                hostname,
+                                record_type,
+                            };
+                            return Some((Ok(query), (Some(lines), number)));
+                        }
+                        Ok(None) => continue,
+                        Err(e) => {
+                            let e = e.context(format!("Skipping input line {}", number));
+                            return Some((Err(e), (Some(lines), number)));
+                        }
+                    },
+                    Ok(None) => return None,
+                    Err(e) => {
+                        let e = anyhow::Error::from(e).context("Failed to read input");
+                        return Some((Err(e), (None, number)));
+                    }
+                }
+            }
+        }
+    }))
+}
@@ -0,0 +1,129 @@
+use crate::Provider;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+/// Caps on how hard a batch may hit the upstream providers
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Upstream queries in flight across all providers
+    pub max_in_flight: Option<usize>,
+    /// Upstream queries in flight to any single provider
+    pub per_provider: Option<usize>,
+    /// Sustained queries per second to any single provider
+    pub qps: Option<f64>,
+}
+
+/// Token bucket allowing `rate` queries per second with bursts of up to `capacity`
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token, returning how long the caller must wait before using it.
+    /// Tokens are reserved even when not yet available, so waiters queue fairly.
+    fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.refilled = now;
+
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Held for the duration of one upstream query; dropping it frees the slots
+pub struct Permit {
+    _global: Option<OwnedSemaphorePermit>,
+    _provider: Option<OwnedSemaphorePermit>,
+}
+
+/// Enforces [`Limits`] for every query sent through the resolver
+pub struct Limiter {
+    limits: Limits,
+    global: Option<Arc<Semaphore>>,
+    providers: Mutex<HashMap<Provider, Arc<Semaphore>>>,
+    buckets: Mutex<HashMap<Provider, TokenBucket>>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            global: limits
+                .max_in_flight
+                .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+            limits,
+            providers: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a query to `provider` is allowed by every configured limit
+    pub async fn acquire(&self, provider: &Provider) -> Permit {
+        let per_provider = match self.limits.per_provider {
+            Some(n) => {
+                let semaphore = Arc::clone(
+                    self.providers
+                        .lock()
+                        .unwrap()
+                        .entry(provider.clone())
+                        .or_insert_with(|| Arc::new(Semaphore::new(n.max(1)))),
+                );
+                Some(semaphore.acquire_owned().await.expect("semaphore closed"))
+            }
+            None => None,
+        };
+
+        if let Some(qps) = self.limits.qps {
+            let wait = self
+                .buckets
+                .lock()
+                .unwrap()
+                .entry(provider.clone())
+                .or_insert_with(|| TokenBucket::new(qps))
+                .take();
+
+            if !wait.is_zero() {
+                info!(?provider, qps, ?wait, "Rate limited");
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        // Taken last so queries waiting on a busy or rate-limited provider
+        // don't hold global slots that other providers could use
+        let global = match &self.global {
+            Some(semaphore) => Some(
+                Arc::clone(semaphore)
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore closed"),
+            ),
+            None => None,
+        };
+
+        Permit {
+            _global: global,
+            _provider: per_provider,
+        }
+    }
+}
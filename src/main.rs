@@ -1,16 +1,65 @@
+mod admin;
+mod blocklist;
+mod cache;
+mod candidates;
+mod consensus;
 mod doh;
 mod doh3;
+mod doh_server;
 mod dot;
 mod ech;
+mod error;
+mod fallback;
+mod hedge;
+mod input;
+mod limits;
+mod message;
+mod metrics;
+mod overrides;
 mod providers;
+mod querylog;
 mod resolver;
+mod retry;
+mod routing;
+mod server;
+mod singleflight;
+mod stats;
+mod subdomains;
+mod telemetry;
+mod timeout;
 
-use clap::{Parser, ValueEnum};
+use admin::AdminServer;
+use blocklist::{BlockPolicy, Blocklist, ListKind};
+use cache::{CachePolicy, DnsCache};
+use candidates::{RejectSinkholes, RequireTxt, Validator};
+use clap::parser::ValueSource;
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use colored::*;
-use resolver::DnsResolver;
-use std::time::Instant;
+use consensus::Consensus;
+use doh_server::DohServer;
+use error::ErrorClass;
+use futures::StreamExt;
+use hedge::HedgePolicy;
+use limits::Limits;
+use metrics::MetricsServer;
+use overrides::Overrides;
+use querylog::{ClientPrivacy, QueryLog, Rotation};
+use resolver::{Config, DnsResolver, Resolved, Strategy};
+use retry::RetryPolicy;
+use routing::Router;
+use serde::{Deserialize, Serialize};
+use server::{Forwarder, StubServer};
+use stats::StatsTracker;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use subdomains::{Expansion, Subdomain};
+use telemetry::LogFormat;
+use timeout::Timeouts;
+use tracing::info;
 
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Protocol {
     /// DNS-over-HTTPS (HTTP/2)
     Doh,
@@ -20,13 +69,36 @@ pub enum Protocol {
     Doh3,
 }
 
-#[derive(Debug, Clone, ValueEnum, PartialEq, Eq, Hash)]
+impl Protocol {
+    pub fn all() -> Vec<Protocol> {
+        vec![Protocol::Doh, Protocol::Dot, Protocol::Doh3]
+    }
+}
+
+#[derive(Clone, ValueEnum, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Provider {
     Cloudflare,
     Google,
     Quad9,
     NextDns,
     Nord,
+    /// An upstream defined in the routing rules, by name
+    #[value(skip)]
+    Custom(String),
+}
+
+// Shown by name everywhere, custom upstreams included
+impl std::fmt::Debug for Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Provider::Cloudflare => write!(f, "Cloudflare"),
+            Provider::Google => write!(f, "Google"),
+            Provider::Quad9 => write!(f, "Quad9"),
+            Provider::NextDns => write!(f, "NextDns"),
+            Provider::Nord => write!(f, "Nord"),
+            Provider::Custom(name) => write!(f, "{}", name),
+        }
+    }
 }
 
 impl Provider {
@@ -86,26 +158,123 @@ impl RecordType {
 #[command(name = "secure-dns-resolver")]
 #[command(about = "A CLI utility for DNS-over-HTTPS, DNS-over-TLS, and DNS-over-HTTP/3 resolution")]
 #[command(version = "0.2.0")]
+#[command(subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("caching").args(["cache", "cache_file"]).multiple(true)))]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Hostnames to resolve (space-separated)
-    #[arg(required = true)]
+    #[arg(required_unless_present = "input")]
     hostnames: Vec<String>,
 
+    /// Read hostnames from a file (`-` for stdin) instead, one per line with an
+    /// optional record type, e.g. `example.com AAAA`; `#` starts a comment
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["hostnames", "consensus", "ech", "all_providers"]
+    )]
+    input: Option<PathBuf>,
+
+    /// Treat the hostnames as base domains and resolve these subdomains of each
+    /// (comma-separated, e.g. `api,pdp,cdn?`); a trailing `?` marks a subdomain as
+    /// optional, `@` is the base domain itself
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        value_name = "LABELS",
+        conflicts_with_all = ["input", "consensus", "ech", "all_providers"]
+    )]
+    subdomains: Vec<Subdomain>,
+
+    /// First-live mode: treat the hostnames as an ordered list of candidate domains and
+    /// report the first one that resolves to a valid answer
+    #[arg(
+        long,
+        conflicts_with_all = ["input", "subdomains", "consensus", "ech", "all_providers"]
+    )]
+    first_live: bool,
+
+    /// In first-live mode, query all candidates at once instead of one after another
+    /// (earlier candidates still take precedence)
+    #[arg(long, requires = "first_live")]
+    race_candidates: bool,
+
+    /// In first-live mode, reject candidates resolving to these addresses (comma-separated);
+    /// unspecified and loopback addresses are always rejected
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "IP",
+        requires = "first_live"
+    )]
+    reject_ip: Vec<IpAddr>,
+
+    /// In first-live mode, require a TXT record containing this marker
+    #[arg(long, value_name = "MARKER", requires = "first_live")]
+    require_txt: Option<String>,
+
+    /// Look the TXT marker up at `<LABEL>.<candidate>` instead of the candidate itself
+    #[arg(long, value_name = "LABEL", requires = "require_txt")]
+    txt_prefix: Option<String>,
+
+    /// Split DNS rules (TOML): per-name upstream, protocols or refusal; names
+    /// matching no rule use the selected strategy
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["consensus", "ech", "all_providers"])]
+    rules: Option<PathBuf>,
+
+    /// Static records answered without asking any upstream, from a hosts file
+    /// (`IP name...`) or zone-like lines (`name [TTL] [IN] A|AAAA|CNAME|TXT value`)
+    #[arg(long = "hosts", value_name = "PATH")]
+    hosts_files: Vec<PathBuf>,
+
+    /// TTL of static answers whose record does not set its own
+    #[arg(long, default_value_t = 300, value_name = "SECS")]
+    static_ttl: u32,
+
+    /// Expose Prometheus metrics at http://ADDR/metrics while running
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// With --input, how many hostnames are resolved at once
+    #[arg(long, default_value_t = 100, value_name = "N")]
+    concurrency: usize,
+
     /// DNS provider to use
     #[arg(short, long, value_enum, default_value = "cloudflare")]
     provider: Provider,
 
-    /// Protocol to use (DoH, DoT, or DoH3)
-    #[arg(short = 'P', long, value_enum, default_value = "doh")]
-    protocol: Protocol,
+    /// Protocol to use (DoH, DoT, or DoH3); a comma-separated list is tried in order
+    /// as a fallback chain, e.g. `doh3,doh,dot`
+    #[arg(
+        short = 'P',
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "doh"
+    )]
+    protocol: Vec<Protocol>,
 
     /// DNS record type to query
     #[arg(short = 't', long, value_enum, default_value = "a")]
     record_type: RecordType,
 
-    /// Show detailed output including all requests and responses
-    #[arg(short, long)]
-    verbose: bool,
+    /// Log what each query does: `-v` for cache, routing, retry and race decisions,
+    /// `-vv` for transport details, `-vvv` for everything (`RUST_LOG` overrides)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// How log output on stderr is formatted
+    #[arg(long, value_enum, default_value = "compact")]
+    log_format: LogFormat,
+
+    /// Export query spans to an OpenTelemetry collector over OTLP/HTTP,
+    /// e.g. `http://localhost:4318` (needs a build with `--features otlp`)
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
 
     /// Query all providers simultaneously
     #[arg(short, long)]
@@ -118,105 +287,1010 @@ struct Args {
     /// Race mode: query all providers simultaneously, use fastest response
     #[arg(short, long)]
     race: bool,
+
+    /// Protocol race: query --provider over every -P protocol at once (default: DoH,
+    /// DoT and DoH3), use fastest response
+    #[arg(long, conflicts_with = "race")]
+    race_protocols: bool,
+
+    /// Consensus mode: query all providers and flag providers whose answers disagree
+    #[arg(long, conflicts_with_all = ["race", "race_protocols"])]
+    consensus: bool,
+
+    /// In consensus mode, stop after this many providers answered (default: wait for all)
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "consensus",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    consensus_min: Option<usize>,
+
+    /// Hedged mode: query --provider first and only ask a second provider if it is slow
+    #[arg(long, conflicts_with_all = ["race", "race_protocols", "consensus"])]
+    hedge: bool,
+
+    /// Adaptive mode: use the provider with the best measured latency instead of racing
+    #[arg(long, conflicts_with_all = ["race", "race_protocols", "consensus", "hedge"])]
+    fastest_known: bool,
+
+    /// Load provider latency history from this file and save it back on exit
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+
+    /// In adaptive mode, re-probe the least recently used provider every N lookups
+    #[arg(long, default_value_t = 20, value_name = "N")]
+    probe_every: u64,
+
+    /// Provider used for the hedge request (default: the built-in provider with the
+    /// best latency history other than --provider)
+    #[arg(long, value_enum, value_name = "PROVIDER", requires = "hedge")]
+    hedge_with: Option<Provider>,
+
+    /// How long to wait for the preferred provider before hedging, in milliseconds
+    #[arg(long, default_value_t = 100, value_name = "MS")]
+    hedge_delay: u64,
+
+    /// Hedge after this latency percentile (0-100) of the preferred provider instead,
+    /// once enough answers have been observed
+    #[arg(long, value_name = "P", requires = "hedge")]
+    hedge_percentile: Option<f64>,
+
+    /// TCP connect timeout per query, in milliseconds
+    #[arg(long, default_value_t = 5000, value_name = "MS")]
+    connect_timeout: u64,
+
+    /// TLS/QUIC handshake timeout per query, in milliseconds
+    #[arg(long, default_value_t = 5000, value_name = "MS")]
+    handshake_timeout: u64,
+
+    /// Timeout for receiving an answer once connected, in milliseconds
+    #[arg(long, default_value_t = 5000, value_name = "MS")]
+    response_timeout: u64,
+
+    /// Overall deadline for the whole batch, in milliseconds
+    #[arg(long, value_name = "MS")]
+    deadline: Option<u64>,
+
+    /// Maximum attempts per query, including the first one (1 disables retries)
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    max_attempts: u32,
+
+    /// Initial retry backoff in milliseconds, doubled on every further attempt
+    #[arg(long, default_value_t = 100, value_name = "MS")]
+    retry_backoff: u64,
+
+    /// Upper bound for a single retry backoff, in milliseconds
+    #[arg(long, default_value_t = 2000, value_name = "MS")]
+    retry_max_backoff: u64,
+
+    /// Error classes that trigger a retry (comma-separated)
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "timeout,connection,handshake,server-error"
+    )]
+    retry_on: Vec<ErrorClass>,
+
+    /// Maximum upstream queries in flight across all providers
+    #[arg(long, value_name = "N")]
+    max_in_flight: Option<usize>,
+
+    /// Maximum upstream queries in flight to any single provider
+    #[arg(long, value_name = "N")]
+    max_per_provider: Option<usize>,
+
+    /// Maximum queries per second sent to any single provider
+    #[arg(long, value_name = "RATE")]
+    qps: Option<f64>,
+
+    /// Cache answers in memory for their TTL, so repeated names skip the network
+    #[arg(long)]
+    cache: bool,
+
+    /// Also keep the cache in this file across runs (implies --cache); defaults to
+    /// the user cache directory for the `cache` subcommands
+    #[arg(long, value_name = "PATH", global = true)]
+    cache_file: Option<PathBuf>,
+
+    /// Keep cached answers at least this long, in seconds, whatever their TTL
+    #[arg(long, default_value_t = 0, value_name = "SECS", requires = "caching")]
+    cache_min_ttl: u64,
+
+    /// Keep cached answers at most this long, in seconds
+    #[arg(
+        long,
+        default_value_t = 86_400,
+        value_name = "SECS",
+        requires = "caching"
+    )]
+    cache_max_ttl: u64,
+
+    /// Cache answers separately per provider instead of sharing them
+    #[arg(long, requires = "caching")]
+    cache_per_provider: bool,
+
+    /// When every upstream fails, serve cached answers up to this many seconds past
+    /// their TTL, flagged as stale (RFC 8767)
+    #[arg(long, value_name = "SECS", requires = "caching")]
+    serve_stale: Option<u64>,
+}
+
+// Parsed once at startup, so the size difference between variants does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect or clear the on-disk answer cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Run as a local stub resolver: answer plain DNS on UDP and TCP (and optionally
+    /// DoH) by forwarding each query with the selected provider, protocol and strategy
+    Serve {
+        /// Address and port to listen on for plain DNS
+        #[arg(long, default_value = "127.0.0.1:53", value_name = "ADDR")]
+        listen: SocketAddr,
+
+        /// Also serve DNS-over-HTTPS at https://ADDR/dns-query (GET and POST)
+        #[arg(long, value_name = "ADDR", requires_all = ["cert", "key"])]
+        doh_listen: Option<SocketAddr>,
+
+        /// PEM certificate chain presented by the DoH listener
+        #[arg(long, value_name = "PATH", requires = "doh_listen")]
+        cert: Option<PathBuf>,
+
+        /// PEM private key (PKCS#8, RSA or EC) for --cert
+        #[arg(long, value_name = "PATH", requires = "doh_listen")]
+        key: Option<PathBuf>,
+
+        /// Block the names on this list (hosts, plain-domain or adblock format); repeatable
+        #[arg(long, value_name = "PATH")]
+        blocklist: Vec<PathBuf>,
+
+        /// Never block the names on this list, in the same formats; repeatable
+        #[arg(long, value_name = "PATH", requires = "blocklist")]
+        allowlist: Vec<PathBuf>,
+
+        /// How blocked names are answered
+        #[arg(long, value_enum, default_value = "nxdomain", requires = "blocklist")]
+        block_policy: BlockPolicy,
+
+        /// Append every answered query to this file as JSON lines
+        #[arg(long, value_name = "PATH")]
+        query_log: Option<PathBuf>,
+
+        /// Rotate the query log once it would grow past this many megabytes
+        #[arg(long, value_name = "MB", requires = "query_log")]
+        query_log_max_size: Option<u64>,
+
+        /// Rotate the query log after this many seconds
+        #[arg(long, value_name = "SECS", requires = "query_log")]
+        query_log_max_age: Option<u64>,
+
+        /// How many rotated query logs to keep (PATH.1 is the newest)
+        #[arg(long, default_value_t = 5, value_name = "N", requires = "query_log")]
+        query_log_keep: usize,
+
+        /// What the query log records about clients
+        #[arg(long, value_enum, default_value = "full", requires = "query_log")]
+        client_ip: ClientPrivacy,
+
+        /// Serve the admin HTTP API (stats, cache, list reload, upstream switching)
+        /// on this loopback address
+        #[arg(long, value_name = "ADDR")]
+        admin_listen: Option<SocketAddr>,
+
+        /// Require `Authorization: Bearer TOKEN` on every admin API request
+        #[arg(long, value_name = "TOKEN", requires = "admin_listen")]
+        admin_token: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// List cached answers and how long they stay valid
+    Show,
+    /// Remove every cached answer
+    Flush,
+}
+
+/// Where the `cache` subcommands look when no --cache-file is given
+fn default_cache_file() -> PathBuf {
+    let dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join("secure-dns-resolver").join("cache.json")
+}
+
+/// Run a `cache` subcommand against the cache file at `path`
+fn run_cache_command(action: &CacheAction, path: &std::path::Path) -> anyhow::Result<()> {
+    match action {
+        CacheAction::Show => {
+            // Show everything in the file, including entries only kept to be served stale
+            let cache = DnsCache::new(CachePolicy {
+                stale_window: Duration::MAX,
+                ..CachePolicy::default()
+            });
+            cache.load(path)?;
+            let entries = cache.entries();
+
+            println!(
+                "{} {} ({} entries)",
+                "Cache:".green().bold(),
+                path.display(),
+                entries.len()
+            );
+            for (key, cached) in entries {
+                let left = if cached.stale {
+                    "stale".to_string()
+                } else {
+                    format!("{}s left", cached.ttl_left.as_secs())
+                };
+                let name = match &key.provider {
+                    Some(provider) => format!("{} [{:?}]", key.name, provider),
+                    None => key.name.clone(),
+                };
+                let answer = if cached.negative {
+                    "no such name or record".dimmed()
+                } else {
+                    message::parse_records(&cached.response)
+                        .map(|records| records.join(", "))
+                        .unwrap_or_default()
+                        .white()
+                };
+                println!(
+                    "  {} {} {} → {} {}",
+                    if cached.negative {
+                        "○".blue()
+                    } else {
+                        "✓".green().bold()
+                    },
+                    name.yellow(),
+                    RecordType::from_code(key.record_type),
+                    answer,
+                    format!("[{:?}/{:?}, {}]", cached.provider, cached.protocol, left).dimmed()
+                );
+            }
+        }
+        CacheAction::Flush => {
+            let flushed = DnsCache::flush(path)?;
+            println!(
+                "{} {} entries from {}",
+                "Flushed".green().bold(),
+                flushed,
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Args {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_millis(self.connect_timeout),
+            handshake: Duration::from_millis(self.handshake_timeout),
+            response: Duration::from_millis(self.response_timeout),
+        }
+    }
+
+    fn hedge_policy(&self) -> HedgePolicy {
+        HedgePolicy {
+            secondary: self.hedge_with.clone().filter(|p| *p != self.provider),
+            delay: Duration::from_millis(self.hedge_delay),
+            percentile: self.hedge_percentile,
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: Duration::from_millis(self.retry_backoff),
+            max_delay: Duration::from_millis(self.retry_max_backoff),
+            retry_on: self.retry_on.clone(),
+        }
+    }
+
+    /// How --input queries are resolved, following the mode flags
+    fn strategy(&self) -> Strategy {
+        if self.race_protocols {
+            Strategy::ProtocolRace(self.provider.clone())
+        } else if self.fastest_known {
+            Strategy::Fastest
+        } else if self.hedge {
+            Strategy::Hedged(self.provider.clone(), self.hedge_policy())
+        } else if self.race {
+            Strategy::Race
+        } else {
+            Strategy::Provider(self.provider.clone())
+        }
+    }
+
+    /// Checks a first-live candidate must pass, cheapest first
+    fn validators(&self) -> Vec<Box<dyn Validator>> {
+        let mut validators: Vec<Box<dyn Validator>> = vec![Box::new(RejectSinkholes {
+            addresses: self.reject_ip.clone(),
+        })];
+        if let Some(marker) = &self.require_txt {
+            validators.push(Box::new(RequireTxt {
+                prefix: self.txt_prefix.clone(),
+                marker: marker.clone(),
+            }));
+        }
+        validators
+    }
+
+    fn cache_policy(&self) -> CachePolicy {
+        CachePolicy {
+            min_ttl: Duration::from_secs(self.cache_min_ttl),
+            max_ttl: Duration::from_secs(self.cache_max_ttl),
+            per_provider: self.cache_per_provider,
+            stale_window: Duration::from_secs(self.serve_stale.unwrap_or(0)),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_in_flight: self.max_in_flight,
+            per_provider: self.max_per_provider,
+            qps: self.qps.filter(|qps| *qps > 0.0),
+        }
+    }
+
+    /// The routing rules from --rules, if given
+    fn router(&self) -> anyhow::Result<Option<Router>> {
+        let router = self.rules.as_deref().map(Router::load).transpose()?;
+        if let Some(router) = &router {
+            for (i, rule) in router.rules().iter().enumerate() {
+                info!(rule = %rule, "Loaded rule {}", i + 1);
+            }
+        }
+        Ok(router)
+    }
+
+    /// The static records from every --hosts file, if any
+    fn overrides(&self) -> anyhow::Result<Option<Overrides>> {
+        let mut overrides = Overrides::new(self.static_ttl);
+        for path in &self.hosts_files {
+            let loaded = overrides.load(path)?;
+            info!(path = %path.display(), "Loaded {} static records", loaded);
+        }
+        Ok((!overrides.is_empty()).then_some(overrides))
+    }
+}
+
+/// Re-read the routing rules (with the `[upstream.*]` tables, which are the provider
+/// configuration), static records and block lists, then swap them in as one; nothing
+/// changes unless every file loads
+fn reload(args: &Args, forwarder: &Forwarder) -> anyhow::Result<()> {
+    let config = Config {
+        router: args.router()?.map(Arc::new),
+        overrides: args.overrides()?.map(Arc::new),
+        blocklist: match forwarder.blocklist() {
+            Some(current) => Some(Arc::new(current.reload()?)),
+            None => None,
+        },
+    };
+    forwarder.resolver().set_config(config);
+    Ok(())
+}
+
+/// Reload the configuration whenever the process receives SIGHUP; a reload that fails
+/// is reported and the running configuration kept
+#[cfg(unix)]
+async fn reload_on_hangup(args: &Args, forwarder: &Forwarder) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).context("Failed to watch for SIGHUP")?;
+    while hangups.recv().await.is_some() {
+        match reload(args, forwarder) {
+            Ok(()) => println!("  {}", "Reloaded configuration (SIGHUP)".dimmed()),
+            Err(e) => eprintln!(
+                "{} Reload failed, keeping the previous configuration: {:#}",
+                "Error:".red().bold(),
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_args: &Args, _forwarder: &Forwarder) -> anyhow::Result<()> {
+    std::future::pending().await
+}
+
+/// Protocol chain as shown in headers, e.g. `Doh3 → Doh`
+fn describe_chain(protocols: &[Protocol]) -> String {
+    protocols
+        .iter()
+        .map(|p| format!("{:?}", p))
+        .collect::<Vec<_>>()
+        .join(" → ")
+}
+
+/// Extra details shown after a single-provider result: protocol fallback and retries
+fn result_note<T>(resolved: &Resolved<T>, protocols: &[Protocol]) -> ColoredString {
+    let mut notes = Vec::new();
+    if resolved.local {
+        notes.push("static".to_string());
+    } else if resolved.stale {
+        notes.push("stale".to_string());
+    } else if resolved.cached {
+        notes.push("cached".to_string());
+    } else if protocols.first() != Some(&resolved.protocol) {
+        notes.push(format!("fell back to {:?}", resolved.protocol));
+    }
+    if resolved.attempts > 1 {
+        notes.push(format!("{} attempts", resolved.attempts));
+    }
+
+    if notes.is_empty() {
+        "".normal()
+    } else if resolved.stale {
+        format!(" ({})", notes.join(", ")).yellow()
+    } else {
+        format!(" ({})", notes.join(", ")).dimmed()
+    }
+}
+
+/// Note shown after a result that needed more than one attempt
+fn attempts_note(attempts: u32) -> ColoredString {
+    if attempts > 1 {
+        format!(" ({} attempts)", attempts).dimmed()
+    } else {
+        "".normal()
+    }
+}
+
+/// Which provider and protocol answered, and how fast, e.g. `via Google/Dot in 21.3ms`
+fn via<T>(resolved: &Resolved<T>) -> String {
+    if resolved.local {
+        return "from static records".to_string();
+    }
+    if resolved.stale {
+        return format!(
+            "stale, from {:?}/{:?}",
+            resolved.provider, resolved.protocol
+        );
+    }
+    if resolved.cached {
+        return format!(
+            "cached, from {:?}/{:?}",
+            resolved.provider, resolved.protocol
+        );
+    }
+    format!(
+        "via {:?}/{:?} in {:.2?}",
+        resolved.provider, resolved.protocol, resolved.elapsed
+    )
+}
+
+/// Print a successful lookup from a mode that picks the provider itself
+fn print_resolved(hostname: &str, resolved: &Resolved<Vec<String>>) {
+    println!(
+        "  {} {} [{}]{} → {}",
+        "✓".green().bold(),
+        hostname.yellow(),
+        if resolved.stale {
+            via(resolved).yellow()
+        } else {
+            via(resolved).normal()
+        },
+        attempts_note(resolved.attempts),
+        resolved.data.join(", ").white()
+    );
+}
+
+/// Print the outcome of a consensus lookup: the majority answer and any deviating providers
+fn print_consensus(hostname: &str, consensus: &Consensus) {
+    let responded = consensus.answers.len();
+
+    match &consensus.majority {
+        Some(answer) if consensus.unanimous() => {
+            println!(
+                "  {} {} [{}/{} agree] → {}",
+                "✓".green().bold(),
+                hostname.yellow(),
+                consensus.agreeing.len(),
+                responded,
+                answer.join(", ").white()
+            );
+        }
+        Some(answer) => {
+            println!(
+                "  {} {} [{}/{} agree, majority] → {}",
+                "⚠".yellow().bold(),
+                hostname.yellow(),
+                consensus.agreeing.len(),
+                responded,
+                answer.join(", ").white()
+            );
+        }
+        None => {
+            println!(
+                "  {} {} [{} answers, no majority]",
+                "⚠".red().bold(),
+                hostname.yellow(),
+                responded
+            );
+        }
+    }
+
+    for provider in &consensus.deviating {
+        let answer = consensus.answer_of(provider).unwrap_or_default();
+        println!(
+            "    {} {:?} → {}",
+            "≠".red().bold(),
+            provider,
+            answer.join(", ").red()
+        );
+    }
+    for (provider, e) in &consensus.failures {
+        println!("    {} {:?} → {}", "✗".red(), provider, e.dimmed());
+    }
+}
+
+/// Title of a mode that picks providers by strategy, e.g. `Race (all providers, fastest wins)`
+fn describe_mode(strategy: &Strategy) -> String {
+    match strategy {
+        Strategy::Provider(provider) => format!("{:?}", provider),
+        Strategy::Race => "Race (all providers, fastest wins)".to_string(),
+        Strategy::ProtocolRace(provider) => {
+            format!("Protocol race (fastest transport wins) {:?}", provider)
+        }
+        Strategy::Fastest => "Fastest known provider (adaptive)".to_string(),
+        Strategy::Hedged(provider, policy) => match &policy.secondary {
+            Some(secondary) => format!("Hedged ({:?}, then {:?})", provider, secondary),
+            None => format!("Hedged ({:?}, then the best other provider)", provider),
+        },
+    }
+}
+
+/// Print the results of a batch in input order, successes through `print`
+fn print_batch(
+    record_type: &RecordType,
+    hostnames: &[String],
+    results: &[anyhow::Result<Resolved<Vec<String>>>],
+    print: impl Fn(&str, &Resolved<Vec<String>>),
+) {
+    println!("  {} Records:", format!("{:?}", record_type).cyan());
+    for (hostname, result) in hostnames.iter().zip(results) {
+        match result {
+            Ok(resolved) => print(hostname, resolved),
+            Err(e) => print_failure(hostname, e),
+        }
+    }
+}
+
+/// Print the ECH configs found in a batch of HTTPS lookups; `note` describes
+/// where each answer came from
+fn print_ech_batch(
+    hostnames: &[String],
+    results: &[anyhow::Result<Resolved<Vec<u8>>>],
+    note: impl Fn(&Resolved<Vec<u8>>) -> String,
+) {
+    println!("{}", "  Fetching ECH Configs...".cyan());
+    for (hostname, result) in hostnames.iter().zip(results) {
+        match result {
+            Ok(resolved) => match ech::parse_ech_config(&resolved.data) {
+                Some(ech_configs) => {
+                    println!(
+                        "  {} {}{} ECH Config:",
+                        "✓".green().bold(),
+                        hostname.yellow(),
+                        note(resolved)
+                    );
+                    for config in ech_configs {
+                        println!("    {}", config.white());
+                    }
+                }
+                None => {
+                    println!(
+                        "  {} {}{} → {}",
+                        "○".blue(),
+                        hostname.yellow(),
+                        note(resolved),
+                        "No ECH config found in HTTPS record".dimmed()
+                    );
+                }
+            },
+            Err(e) => print_failure(hostname, e),
+        }
+    }
+    println!("{}", "─".repeat(50).dimmed());
+}
+
+/// Print a failed lookup, marking timeouts distinctly from other errors
+fn print_failure(hostname: &str, e: &anyhow::Error) {
+    if error::is_timeout(e) {
+        println!(
+            "  {} {} → {}",
+            "⏱".yellow().bold(),
+            hostname.yellow(),
+            e.to_string().yellow()
+        );
+    } else {
+        println!(
+            "  {} {} → {}",
+            "✗".red().bold(),
+            hostname.yellow(),
+            e.to_string().red()
+        );
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    // Racing the default protocol alone would be no race at all
+    if args.race_protocols && matches.value_source("protocol") == Some(ValueSource::DefaultValue) {
+        args.protocol = Protocol::all();
+    }
+    let _telemetry = telemetry::init(args.verbose, args.log_format, args.otlp_endpoint.as_deref())?;
+
+    if let Some(Command::Cache { action }) = &args.command {
+        let path = args.cache_file.clone().unwrap_or_else(default_cache_file);
+        return run_cache_command(action, &path);
+    }
 
     println!("{}", "═".repeat(60).cyan());
     println!("{}", "  Secure DNS Resolver".bold().cyan());
     println!("{}", "═".repeat(60).cyan());
 
-    if args.verbose {
-        println!("{}", "  [verbose] Verbose mode enabled".dimmed());
+    info!(
+        protocol = %describe_chain(&args.protocol),
+        record_type = ?args.record_type,
+        hostnames = ?args.hostnames,
+        "Starting"
+    );
+
+    let start = Instant::now();
+
+    let resolver = DnsResolver::new(args.timeouts())
+        .with_deadline(args.deadline.map(Duration::from_millis))
+        .with_retry(args.retry_policy())
+        .with_limits(args.limits())
+        .with_cache((args.cache || args.cache_file.is_some()).then(|| args.cache_policy()))
+        .with_router(args.router()?)
+        .with_overrides(args.overrides()?)
+        .with_stats(StatsTracker::new(args.probe_every));
+
+    if let Some(path) = &args.state_file {
+        if let Err(e) = resolver.stats().load(path) {
+            eprintln!("{} {:#}", "Warning:".yellow().bold(), e);
+        }
+    }
+
+    if let (Some(path), Some(cache)) = (&args.cache_file, resolver.cache()) {
+        match cache.load(path) {
+            Ok(loaded) => info!(path = %path.display(), "Loaded {} cache entries", loaded),
+            Err(e) => eprintln!("{} {:#}", "Warning:".yellow().bold(), e),
+        }
+    }
+
+    if let Some(addr) = args.metrics_listen {
+        let server = MetricsServer::bind(addr, resolver.clone()).await?;
+        tokio::spawn(server.run());
+        println!("  {} http://{}/metrics", "Metrics:".dimmed(), addr);
+    }
+
+    if let Some(Command::Serve {
+        listen,
+        doh_listen,
+        cert,
+        key,
+        blocklist,
+        allowlist,
+        block_policy,
+        query_log,
+        query_log_max_size,
+        query_log_max_age,
+        query_log_keep,
+        client_ip,
+        admin_listen,
+        admin_token,
+    }) = &args.command
+    {
+        // Stub resolver: relay local plain-DNS (and DoH) queries until interrupted
+        let strategy = args.strategy();
+        let protocols = args.protocol.clone();
+        let mut listeners = format!("{} (UDP, TCP)", listen);
+        if let Some(addr) = doh_listen {
+            listeners.push_str(&format!(", https://{}/dns-query", addr));
+        }
         println!(
-            "{}",
-            format!("  [verbose] Protocol: {:?}", args.protocol).dimmed()
+            "\n{} {} {} via {}",
+            "▶ Serving:".green().bold(),
+            listeners.cyan(),
+            format!("({})", strategy).dimmed(),
+            describe_chain(&protocols)
         );
-        println!(
-            "{}",
-            format!("  [verbose] Record type: {:?}", args.record_type).dimmed()
+
+        let blocklist = (!blocklist.is_empty())
+            .then(|| Blocklist::load(blocklist, allowlist, *block_policy))
+            .transpose()?;
+        if let Some(blocklist) = &blocklist {
+            for list in blocklist.lists() {
+                println!(
+                    "  {} {} {}",
+                    if list.kind == ListKind::Block {
+                        "Blocking:"
+                    } else {
+                        "Allowing:"
+                    }
+                    .dimmed(),
+                    list.path.display(),
+                    format!("({} entries)", list.entries).dimmed()
+                );
+            }
+        }
+
+        let query_log = query_log
+            .as_deref()
+            .map(|path| {
+                let rotation = Rotation {
+                    max_bytes: query_log_max_size.map(|mb| mb * 1024 * 1024),
+                    max_age: query_log_max_age.map(Duration::from_secs),
+                    keep: *query_log_keep,
+                };
+                QueryLog::open(path, rotation, *client_ip)
+            })
+            .transpose()?;
+        if let Some(log) = &query_log {
+            println!(
+                "  {} {} {}",
+                "Logging queries:".dimmed(),
+                log.path().display(),
+                format!("(client addresses: {:?})", client_ip)
+                    .to_lowercase()
+                    .dimmed()
+            );
+        }
+
+        let forwarder = Arc::new(
+            Forwarder::new(resolver.clone(), protocols, strategy)
+                .with_blocklist(blocklist)
+                .with_query_log(query_log),
         );
+        let doh = match (doh_listen, cert, key) {
+            (Some(addr), Some(cert), Some(key)) => {
+                Some((DohServer::new(Arc::clone(&forwarder), cert, key)?, *addr))
+            }
+            _ => None,
+        };
+        if let Some(addr) = admin_listen {
+            println!(
+                "  {} http://{}/ {}",
+                "Admin API:".dimmed(),
+                addr,
+                "(stats, cache, blocklist/reload, upstream)".dimmed()
+            );
+        }
+        println!("{}", "  Press Ctrl-C to stop".dimmed());
+
+        let plain = StubServer::new(Arc::clone(&forwarder)).run(*listen);
+        let doh = async {
+            match doh {
+                Some((doh, addr)) => doh.run(addr).await,
+                None => std::future::pending().await,
+            }
+        };
+        let admin = async {
+            match admin_listen {
+                Some(addr) => {
+                    AdminServer::new(Arc::clone(&forwarder))
+                        .with_token(admin_token.clone())
+                        .run(*addr)
+                        .await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let reloads = reload_on_hangup(&args, &forwarder);
+        let servers = async { tokio::try_join!(plain, doh, admin, reloads).map(|_| ()) };
+        tokio::select! {
+            result = servers => result?,
+            _ = tokio::signal::ctrl_c() => println!("\n{}", "  Stopped".dimmed()),
+        }
+
+        if let Some(blocklist) = forwarder.blocklist() {
+            println!("\n{}", "▶ List hits:".green().bold());
+            for list in blocklist.lists() {
+                println!(
+                    "  {} {} {}",
+                    list.path.display(),
+                    format!("{} hits", list.hits()).cyan(),
+                    if list.kind == ListKind::Block {
+                        "(blocklist)"
+                    } else {
+                        "(allowlist)"
+                    }
+                    .dimmed()
+                );
+            }
+        }
+    } else if let Some(path) = &args.input {
+        // Streaming mode: read hostnames from a file or stdin, print results as they complete
+        let strategy = args.strategy();
+        let protocols = args.protocol.clone();
         println!(
-            "{}",
-            format!("  [verbose] Hostnames: {:?}", args.hostnames).dimmed()
+            "\n{} {} {} via {}",
+            "▶ Input:".green().bold(),
+            path.display().to_string().cyan(),
+            format!("({})", strategy).dimmed(),
+            describe_chain(&protocols)
         );
-    }
+        println!("{}", "─".repeat(50).dimmed());
 
-    let start = Instant::now();
+        let queries = input::read_queries(path, args.record_type.clone())
+            .await?
+            .filter_map(|query| async move {
+                match query {
+                    Ok(query) => Some(query),
+                    Err(e) => {
+                        eprintln!("{} {:#}", "Warning:".yellow().bold(), e);
+                        None
+                    }
+                }
+            });
 
-    let resolver = DnsResolver::new();
+        let mut results =
+            Box::pin(resolver.resolve_stream(queries, &protocols, strategy, args.concurrency));
 
-    // Race mode: query all providers, use fastest response
-    if args.race {
+        let (mut resolved, mut failed) = (0usize, 0usize);
+        while let Some((query, result)) = results.next().await {
+            let label = if query.record_type == args.record_type {
+                query.hostname
+            } else {
+                format!("{} {:?}", query.hostname, query.record_type)
+            };
+            match result {
+                Ok(answer) => {
+                    resolved += 1;
+                    print_resolved(&label, &answer);
+                }
+                Err(e) => {
+                    failed += 1;
+                    print_failure(&label, &e);
+                }
+            }
+        }
+
+        println!("{}", "─".repeat(50).dimmed());
         println!(
-            "\n{} {} via {:?}",
-            "▶ Mode:".green().bold(),
-            "Race (all providers, fastest wins)".cyan(),
-            args.protocol
+            "  {} resolved, {} failed",
+            resolved.to_string().green(),
+            failed.to_string().red()
+        );
+    } else if !args.subdomains.is_empty() {
+        // Subdomain expansion: every label under every base domain, grouped by base
+        let strategy = args.strategy();
+        let protocols = args.protocol.clone();
+        let expansion = Expansion::new(&args.hostnames, &args.subdomains);
+        let hostnames = expansion.hostnames();
+        println!(
+            "\n{} {} {} via {}",
+            "▶ Subdomains:".green().bold(),
+            args.subdomains
+                .iter()
+                .map(|s| if s.required {
+                    s.label.clone()
+                } else {
+                    format!("{}?", s.label)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+                .cyan(),
+            format!("({})", strategy).dimmed(),
+            describe_chain(&protocols)
         );
         println!("{}", "─".repeat(50).dimmed());
 
-        // ECH resolution with race
-        if args.ech {
-            println!("{}", "  Fetching ECH Configs...".cyan());
+        let results = resolver
+            .resolve_batch_strategy(&hostnames, &protocols, strategy, &args.record_type)
+            .await;
 
-            let ech_results = resolver
-                .resolve_batch_race_raw(
-                    &args.hostnames,
-                    &args.protocol,
-                    65, // HTTPS record type
-                    args.verbose,
-                )
-                .await;
+        let groups = expansion.group(results);
+        let healthy = groups.iter().filter(|group| group.healthy()).count();
 
-            for (hostname, result) in args.hostnames.iter().zip(ech_results.iter()) {
+        for group in &groups {
+            if group.healthy() {
+                println!("  {} {}", "●".green().bold(), group.base.bold());
+            } else {
+                println!(
+                    "  {} {} {}",
+                    "●".red().bold(),
+                    group.base.bold(),
+                    "(required subdomain failed)".red()
+                );
+            }
+            for (subdomain, hostname, result) in &group.entries {
+                let label = if subdomain.required {
+                    hostname.clone()
+                } else {
+                    format!("{} (optional)", hostname)
+                };
                 match result {
-                    Ok((raw_data, provider, elapsed)) => match ech::parse_ech_config(raw_data) {
-                        Some(ech_configs) => {
-                            println!(
-                                "  {} {} [via {:?} in {:.2?}] ECH Config:",
-                                "✓".green().bold(),
-                                hostname.yellow(),
-                                provider,
-                                elapsed,
-                            );
-                            for config in ech_configs {
-                                println!("    {}", config.white());
-                            }
-                        }
-                        None => {
-                            println!(
-                                "  {} {} [via {:?} in {:.2?}] → {}",
-                                "○".blue(),
-                                hostname.yellow(),
-                                provider,
-                                elapsed,
-                                "No ECH config found in HTTPS record".dimmed()
-                            );
-                        }
-                    },
-                    Err(e) => {
-                        println!(
-                            "  {} {} → {}",
-                            "✗".red().bold(),
-                            hostname.yellow(),
-                            e.to_string().red()
-                        );
-                    }
+                    Ok(resolved) => print_resolved(&label, resolved),
+                    Err(e) => print_failure(&label, e),
                 }
             }
-            println!("{}", "─".repeat(50).dimmed());
         }
 
-        // Regular record resolution with race
+        println!("{}", "─".repeat(50).dimmed());
+        println!(
+            "  {}/{} base domains healthy",
+            healthy.to_string().green(),
+            groups.len()
+        );
+    } else if args.first_live {
+        // First-live mode: the first candidate, in list order, that resolves and validates
+        let strategy = args.strategy();
+        let protocols = args.protocol.clone();
+        println!(
+            "\n{} {} {} via {}",
+            "▶ First live:".green().bold(),
+            format!(
+                "{} candidates, {}",
+                args.hostnames.len(),
+                if args.race_candidates {
+                    "raced"
+                } else {
+                    "in order"
+                }
+            )
+            .cyan(),
+            format!("({})", strategy).dimmed(),
+            describe_chain(&protocols)
+        );
+        println!("{}", "─".repeat(50).dimmed());
+
+        let result = resolver
+            .resolve_first_live(
+                &args.hostnames,
+                &protocols,
+                &strategy,
+                &args.record_type,
+                &args.validators(),
+                args.race_candidates,
+            )
+            .await;
+
+        match result {
+            Ok(live) => {
+                for (candidate, reason) in &live.rejected {
+                    println!(
+                        "  {} {} → {}",
+                        "○".blue(),
+                        candidate.yellow(),
+                        reason.dimmed()
+                    );
+                }
+                print_resolved(&live.domain, &live.resolved);
+            }
+            Err(e) => print_failure("candidates", &e),
+        }
+    } else if args.consensus {
+        println!(
+            "\n{} {} via {}",
+            "▶ Mode:".green().bold(),
+            "Consensus (all providers, compare answers)".cyan(),
+            describe_chain(&args.protocol)
+        );
+        println!("{}", "─".repeat(50).dimmed());
+
         let results = resolver
-            .resolve_batch_race(
+            .resolve_batch_consensus(
                 &args.hostnames,
                 &args.protocol,
                 &args.record_type,
-                args.verbose,
+                args.consensus_min,
             )
             .await;
 
@@ -225,27 +1299,11 @@ async fn main() -> anyhow::Result<()> {
 
         for (hostname, result) in args.hostnames.iter().zip(results.iter()) {
             match result {
-                Ok((addresses, provider, elapsed)) => {
-                    println!(
-                        "  {} {} [via {:?} in {:.2?}] → {}",
-                        "✓".green().bold(),
-                        hostname.yellow(),
-                        provider,
-                        elapsed,
-                        addresses.join(", ").white()
-                    );
-                }
-                Err(e) => {
-                    println!(
-                        "  {} {} → {}",
-                        "✗".red().bold(),
-                        hostname.yellow(),
-                        e.to_string().red()
-                    );
-                }
+                Ok(consensus) => print_consensus(hostname, consensus),
+                Err(e) => print_failure(hostname, e),
             }
         }
-    } else {
+    } else if args.rules.is_none() && matches!(args.strategy(), Strategy::Provider(_)) {
         // Original behavior: single or all providers
         let providers: Vec<Provider> = if args.all_providers {
             Provider::all()
@@ -255,96 +1313,96 @@ async fn main() -> anyhow::Result<()> {
 
         for provider in &providers {
             println!(
-                "\n{} {:?} via {:?}",
+                "\n{} {:?} via {}",
                 "▶ Provider:".green().bold(),
                 provider,
-                args.protocol
+                describe_chain(&args.protocol)
             );
             println!("{}", "─".repeat(50).dimmed());
 
-            // ECH resolution
+            let strategy = Strategy::Provider(provider.clone());
+
             if args.ech {
-                println!("{}", "  Fetching ECH Configs...".cyan());
-
-                let ech_results = resolver
-                    .resolve_batch_raw(
-                        &args.hostnames,
-                        provider,
-                        &args.protocol,
-                        65, // HTTPS record type
-                        args.verbose,
-                    )
+                let results = resolver
+                    .resolve_batch_raw(&args.hostnames, &args.protocol, strategy.clone(), 65)
                     .await;
-
-                for (hostname, result) in args.hostnames.iter().zip(ech_results.iter()) {
-                    match result {
-                        Ok(raw_data) => match ech::parse_ech_config(raw_data) {
-                            Some(ech_configs) => {
-                                println!(
-                                    "  {} {} ECH Config:",
-                                    "✓".green().bold(),
-                                    hostname.yellow(),
-                                );
-                                for config in ech_configs {
-                                    println!("    {}", config.white());
-                                }
-                            }
-                            None => {
-                                println!(
-                                    "  {} {} → {}",
-                                    "○".blue(),
-                                    hostname.yellow(),
-                                    "No ECH config found in HTTPS record".dimmed()
-                                );
-                            }
-                        },
-                        Err(e) => {
-                            println!(
-                                "  {} {} → {}",
-                                "✗".red().bold(),
-                                hostname.yellow(),
-                                e.to_string().red()
-                            );
-                        }
-                    }
-                }
-                println!("{}", "─".repeat(50).dimmed());
+                print_ech_batch(&args.hostnames, &results, |resolved| {
+                    result_note(resolved, &args.protocol).to_string()
+                });
             }
 
             // Regular record resolution - all hostnames sent concurrently
             let results = resolver
-                .resolve_batch(
+                .resolve_batch_strategy(
                     &args.hostnames,
-                    provider,
                     &args.protocol,
+                    strategy,
                     &args.record_type,
-                    args.verbose,
                 )
                 .await;
+            print_batch(
+                &args.record_type,
+                &args.hostnames,
+                &results,
+                |hostname, resolved| {
+                    println!(
+                        "  {} {}{} → {}",
+                        "✓".green().bold(),
+                        hostname.yellow(),
+                        result_note(resolved, &args.protocol),
+                        resolved.data.join(", ").white()
+                    )
+                },
+            );
+        }
+    } else {
+        // Every other mode resolves one record type the way its strategy says
+        let strategy = args.strategy();
+        let protocols = &args.protocol;
+        match &args.rules {
+            Some(path) => println!(
+                "\n{} {} {} via {}",
+                "▶ Rules:".green().bold(),
+                path.display().to_string().cyan(),
+                format!("(otherwise {})", strategy).dimmed(),
+                describe_chain(protocols)
+            ),
+            None => println!(
+                "\n{} {} via {}",
+                "▶ Mode:".green().bold(),
+                describe_mode(&strategy).cyan(),
+                match strategy {
+                    Strategy::ProtocolRace(_) => describe_chain(protocols).replace(" → ", " | "),
+                    _ => describe_chain(protocols),
+                }
+            ),
+        }
+        println!("{}", "─".repeat(50).dimmed());
+
+        if args.ech {
+            let results = resolver
+                .resolve_batch_raw(&args.hostnames, protocols, strategy.clone(), 65)
+                .await;
+            print_ech_batch(&args.hostnames, &results, |resolved| {
+                format!(" [{}]{}", via(resolved), attempts_note(resolved.attempts))
+            });
+        }
 
-            let record_type_str = format!("{:?}", args.record_type);
-            println!("  {} Records:", record_type_str.cyan());
+        let results = resolver
+            .resolve_batch_strategy(&args.hostnames, protocols, strategy, &args.record_type)
+            .await;
+        print_batch(&args.record_type, &args.hostnames, &results, print_resolved);
+    }
 
-            for (hostname, result) in args.hostnames.iter().zip(results.iter()) {
-                match result {
-                    Ok(addresses) => {
-                        println!(
-                            "  {} {} → {}",
-                            "✓".green().bold(),
-                            hostname.yellow(),
-                            addresses.join(", ").white()
-                        );
-                    }
-                    Err(e) => {
-                        println!(
-                            "  {} {} → {}",
-                            "✗".red().bold(),
-                            hostname.yellow(),
-                            e.to_string().red()
-                        );
-                    }
-                }
-            }
+    if let (Some(path), Some(cache)) = (&args.cache_file, resolver.cache()) {
+        if let Err(e) = cache.save(path) {
+            eprintln!("{} {:#}", "Warning:".yellow().bold(), e);
+        }
+    }
+
+    if let Some(path) = &args.state_file {
+        if let Err(e) = resolver.stats().save(path) {
+            eprintln!("{} {:#}", "Warning:".yellow().bold(), e);
         }
     }
 
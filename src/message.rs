@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::rdata::{A, AAAA};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType as DnsRecordType};
+use trust_dns_proto::serialize::binary::BinEncodable;
+
+/// Encode a recursive query for `hostname` with a random message ID
+pub fn build_query(hostname: &str, record_type: u16) -> Result<Vec<u8>> {
+    let name = Name::from_ascii(hostname).context("Invalid hostname")?;
+    let record_type = DnsRecordType::from(record_type);
+
+    let mut message = Message::new();
+    message.set_id(rand::random());
+    message.set_message_type(MessageType::Query);
+    message.set_op_code(OpCode::Query);
+    message.set_recursion_desired(true);
+
+    let query = Query::query(name, record_type);
+    message.add_query(query);
+
+    let bytes = message.to_bytes().context("Failed to encode DNS query")?;
+    Ok(bytes)
+}
+
+/// Every answer record of a response in presentation format
+pub fn parse_records(data: &[u8]) -> Result<Vec<String>> {
+    let message = Message::from_vec(data).context("Failed to parse DNS response")?;
+
+    let mut results = Vec::new();
+
+    for answer in message.answers() {
+        let rdata = answer.data().map(|d| format!("{}", d));
+        if let Some(data) = rdata {
+            results.push(data);
+        }
+    }
+
+    if results.is_empty() {
+        anyhow::bail!("No records found");
+    }
+
+    Ok(results)
+}
+
+/// RDATA of the first answer record, undecoded (for ECH parsing)
+pub fn extract_raw_rdata(data: &[u8]) -> Result<Vec<u8>> {
+    let message = Message::from_vec(data).context("Failed to parse DNS response")?;
+
+    for answer in message.answers() {
+        if let Some(rdata) = answer.data() {
+            if let Ok(bytes) = rdata.to_bytes() {
+                return Ok(bytes);
+            }
+        }
+    }
+
+    anyhow::bail!("No RDATA found in response")
+}
+
+/// How long a response may be cached, or `None` if it must not be.
+///
+/// Positive answers live as long as their shortest record TTL. Negative answers
+/// (NXDOMAIN, or NOERROR without answers) use the SOA in the authority section,
+/// capped at its MINIMUM field as RFC 2308 requires; without a SOA they are not
+/// cached. Server failures are never cached.
+pub fn cache_ttl(data: &[u8]) -> Option<Duration> {
+    let message = Message::from_vec(data).ok()?;
+
+    let ttl = match message.response_code() {
+        ResponseCode::NoError if !message.answers().is_empty() => {
+            message.answers().iter().map(|r| r.ttl()).min()?
+        }
+        ResponseCode::NoError | ResponseCode::NXDomain => {
+            message.name_servers().iter().find_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(r.ttl().min(soa.minimum())),
+                _ => None,
+            })?
+        }
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(ttl as u64))
+}
+
+/// Re-encode a response with the TTL of every record replaced by `ttl(old)`
+pub fn rewrite_ttls(data: &[u8], ttl: impl Fn(u32) -> u32) -> Result<Vec<u8>> {
+    let mut message = Message::from_vec(data).context("Failed to parse DNS response")?;
+    let rewrite = |records: &mut Vec<Record>| {
+        for record in records {
+            record.set_ttl(ttl(record.ttl()));
+        }
+    };
+    rewrite(message.answers_mut());
+    rewrite(message.name_servers_mut());
+    rewrite(message.additionals_mut());
+    message.to_bytes().context("Failed to encode DNS response")
+}
+
+/// Whether a response says the name or record type does not exist
+pub fn is_negative(data: &[u8]) -> bool {
+    Message::from_vec(data)
+        .map(|m| m.response_code() == ResponseCode::NXDomain || m.answers().is_empty())
+        .unwrap_or(false)
+}
+
+/// Response code of a wire-format response
+pub fn response_code(data: &[u8]) -> Result<ResponseCode> {
+    let message = Message::from_vec(data).context("Failed to parse DNS response")?;
+    Ok(message.response_code())
+}
+
+/// The message ID of a wire-format message
+pub fn id(data: &[u8]) -> u16 {
+    match data {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    }
+}
+
+/// Overwrite the message ID of a wire-format message
+pub fn set_id(data: &mut [u8], id: u16) {
+    if data.len() >= 2 {
+        data[..2].copy_from_slice(&id.to_be_bytes());
+    }
+}
+
+/// A reply to `request` carrying only its question and `code`
+pub fn reply(request: &Message, code: ResponseCode) -> Result<Vec<u8>> {
+    response_to(request, code)
+        .to_bytes()
+        .context("Failed to encode DNS response")
+}
+
+/// A reply telling the client the answer does not fit and it should retry over TCP
+pub fn truncated(request: &Message) -> Result<Vec<u8>> {
+    let mut message = response_to(request, ResponseCode::NoError);
+    message.set_truncated(true);
+    message.to_bytes().context("Failed to encode DNS response")
+}
+
+/// A reply to `request` answering address queries with 0.0.0.0 or ::, and anything
+/// else with no records
+pub fn null_answer(request: &Message, ttl: u32) -> Result<Vec<u8>> {
+    let mut message = response_to(request, ResponseCode::NoError);
+    for query in request.queries() {
+        let rdata = match query.query_type() {
+            DnsRecordType::A => RData::A(A(Ipv4Addr::UNSPECIFIED)),
+            DnsRecordType::AAAA => RData::AAAA(AAAA(Ipv6Addr::UNSPECIFIED)),
+            _ => continue,
+        };
+        message.add_answer(Record::from_rdata(query.name().clone(), ttl, rdata));
+    }
+    message.to_bytes().context("Failed to encode DNS response")
+}
+
+/// A NOERROR response to a `hostname` query carrying `answers`, for names answered
+/// without asking an upstream; an empty `answers` means the type has no records
+pub fn synthesize(hostname: &str, record_type: u16, answers: Vec<Record>) -> Result<Vec<u8>> {
+    let name = Name::from_ascii(hostname).context("Invalid hostname")?;
+    let mut request = Message::new();
+    request.set_op_code(OpCode::Query);
+    request.set_recursion_desired(true);
+    request.add_query(Query::query(name, DnsRecordType::from(record_type)));
+
+    let mut message = response_to(&request, ResponseCode::NoError);
+    message.set_authoritative(true);
+    message.add_answers(answers);
+    message.to_bytes().context("Failed to encode DNS response")
+}
+
+fn response_to(request: &Message, code: ResponseCode) -> Message {
+    let mut message = Message::new();
+    message.set_id(request.id());
+    message.set_message_type(MessageType::Response);
+    message.set_op_code(request.op_code());
+    message.set_recursion_desired(request.recursion_desired());
+    message.set_recursion_available(true);
+    message.set_response_code(code);
+    message.add_queries(request.queries().iter().cloned());
+    message
+}
@@ -0,0 +1,415 @@
+use crate::cache::DnsCache;
+use crate::error::Phase;
+use crate::querylog::Status;
+use crate::resolver::DnsResolver;
+use crate::server::{self, Transport};
+use crate::{Protocol, Provider};
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use trust_dns_proto::rr::RecordType as DnsRecordType;
+
+/// Upper bounds (seconds) of the latency histogram buckets
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Cumulative latency histogram in the Prometheus layout
+#[derive(Default, Clone)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Upstream responses by provider, protocol, record type and response code
+type QueryKey = (String, Protocol, u16, String);
+
+/// Counters and histograms fed by the resolver and the transports, rendered in the
+/// Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    queries: Mutex<HashMap<QueryKey, u64>>,
+    /// Keyed by protocol and phase name (`connect`, `handshake`, `response`, `total`,
+    /// and `exchange` for DoH, whose setup cannot be told apart from the request)
+    phases: Mutex<HashMap<(Protocol, &'static str), Histogram>>,
+    race_wins: Mutex<HashMap<(String, Protocol), u64>>,
+    served: Mutex<HashMap<(Transport, Status), u64>>,
+    in_flight: Mutex<HashMap<Protocol, i64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+/// Counts one exchange as in flight until dropped
+pub struct InFlight<'a> {
+    metrics: &'a Metrics,
+    protocol: Protocol,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        *self
+            .metrics
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(self.protocol)
+            .or_default() -= 1;
+    }
+}
+
+impl Metrics {
+    /// One upstream answer; `rcode` is `None` when no answer arrived
+    pub fn query(
+        &self,
+        provider: &Provider,
+        protocol: Protocol,
+        type_code: u16,
+        rcode: Option<String>,
+    ) {
+        let key = (
+            format!("{:?}", provider),
+            protocol,
+            type_code,
+            rcode.unwrap_or_else(|| "error".to_string()),
+        );
+        *self.queries.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    /// How long one phase of a transport exchange took
+    pub fn phase(&self, protocol: Protocol, phase: Phase, elapsed: Duration) {
+        let name = match phase {
+            Phase::Connect => "connect",
+            Phase::Handshake => "handshake",
+            Phase::Response => "response",
+            Phase::Exchange => "exchange",
+        };
+        self.observe(protocol, name, elapsed);
+    }
+
+    /// How long a whole transport exchange took
+    pub fn total(&self, protocol: Protocol, elapsed: Duration) {
+        self.observe(protocol, "total", elapsed);
+    }
+
+    fn observe(&self, protocol: Protocol, phase: &'static str, elapsed: Duration) {
+        self.phases
+            .lock()
+            .unwrap()
+            .entry((protocol, phase))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn race_win(&self, provider: &Provider, protocol: Protocol) {
+        let key = (format!("{:?}", provider), protocol);
+        *self.race_wins.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    /// One query answered by `serve`, by listener transport and how it was answered
+    pub fn served(&self, transport: Transport, status: Status) {
+        *self
+            .served
+            .lock()
+            .unwrap()
+            .entry((transport, status))
+            .or_default() += 1;
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an exchange over `protocol` as in flight for as long as the guard lives;
+    /// DoT and DoH3 open a connection per exchange, so for them this is the number of
+    /// open upstream connections
+    pub fn in_flight(&self, protocol: Protocol) -> InFlight<'_> {
+        *self.in_flight.lock().unwrap().entry(protocol).or_default() += 1;
+        InFlight {
+            metrics: self,
+            protocol,
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format
+    pub fn render(&self, cache: Option<&DnsCache>) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "secure_dns_upstream_queries_total",
+            "counter",
+            "Upstream answers by provider, protocol, record type and response code",
+        );
+        let queries: BTreeMap<_, _> = self
+            .queries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((provider, protocol, type_code, rcode), count)| {
+                let labels = format!(
+                    "provider=\"{}\",protocol=\"{:?}\",type=\"{}\",rcode=\"{}\"",
+                    escape(provider),
+                    protocol,
+                    DnsRecordType::from(*type_code),
+                    rcode
+                );
+                (labels, *count)
+            })
+            .collect();
+        for (labels, count) in queries {
+            let _ = writeln!(
+                out,
+                "secure_dns_upstream_queries_total{{{}}} {}",
+                labels, count
+            );
+        }
+
+        header(
+            &mut out,
+            "secure_dns_upstream_phase_seconds",
+            "histogram",
+            "Time spent per transport phase of upstream exchanges",
+        );
+        let phases: BTreeMap<_, _> = self
+            .phases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((protocol, phase), histogram)| {
+                let labels = format!("protocol=\"{:?}\",phase=\"{}\"", protocol, phase);
+                (labels, histogram.clone())
+            })
+            .collect();
+        for (labels, histogram) in phases {
+            for (bound, count) in BUCKETS.iter().zip(histogram.counts) {
+                let _ = writeln!(
+                    out,
+                    "secure_dns_upstream_phase_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "secure_dns_upstream_phase_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "secure_dns_upstream_phase_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "secure_dns_upstream_phase_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+
+        header(
+            &mut out,
+            "secure_dns_race_wins_total",
+            "counter",
+            "Races won per provider and protocol",
+        );
+        let wins: BTreeMap<_, _> = self
+            .race_wins
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((provider, protocol), count)| {
+                let labels = format!(
+                    "provider=\"{}\",protocol=\"{:?}\"",
+                    escape(provider),
+                    protocol
+                );
+                (labels, *count)
+            })
+            .collect();
+        for (labels, count) in wins {
+            let _ = writeln!(out, "secure_dns_race_wins_total{{{}}} {}", labels, count);
+        }
+
+        header(
+            &mut out,
+            "secure_dns_served_queries_total",
+            "counter",
+            "Client queries answered in serve mode by transport and outcome",
+        );
+        let served: BTreeMap<_, _> = self
+            .served
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((transport, status), count)| {
+                let transport = format!("{:?}", transport).to_lowercase();
+                let status = format!("{:?}", status).to_lowercase();
+                ((transport, status), *count)
+            })
+            .collect();
+        for ((transport, status), count) in served {
+            let _ = writeln!(
+                out,
+                "secure_dns_served_queries_total{{transport=\"{}\",status=\"{}\"}} {}",
+                transport, status, count
+            );
+        }
+
+        header(
+            &mut out,
+            "secure_dns_upstream_in_flight",
+            "gauge",
+            "Upstream exchanges in progress; open connections for DoT and DoH3",
+        );
+        let in_flight: BTreeMap<_, _> = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(protocol, count)| (format!("{:?}", protocol), *count))
+            .collect();
+        for (protocol, count) in in_flight {
+            let _ = writeln!(
+                out,
+                "secure_dns_upstream_in_flight{{protocol=\"{}\"}} {}",
+                protocol, count
+            );
+        }
+
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+        header(
+            &mut out,
+            "secure_dns_cache_hits_total",
+            "counter",
+            "Cache lookups answered from the cache",
+        );
+        let _ = writeln!(out, "secure_dns_cache_hits_total {}", hits);
+        header(
+            &mut out,
+            "secure_dns_cache_misses_total",
+            "counter",
+            "Cache lookups that went upstream",
+        );
+        let _ = writeln!(out, "secure_dns_cache_misses_total {}", misses);
+        header(
+            &mut out,
+            "secure_dns_cache_hit_ratio",
+            "gauge",
+            "Share of cache lookups answered from the cache",
+        );
+        let ratio = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        let _ = writeln!(out, "secure_dns_cache_hit_ratio {}", ratio);
+        if let Some(cache) = cache {
+            header(
+                &mut out,
+                "secure_dns_cache_entries",
+                "gauge",
+                "Answers held in the cache",
+            );
+            let _ = writeln!(out, "secure_dns_cache_entries {}", cache.len());
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value (custom upstream names come from the rules file)
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Plain HTTP listener exposing `GET /metrics` for a resolver
+pub struct MetricsServer {
+    listener: TcpListener,
+    resolver: DnsResolver,
+}
+
+impl MetricsServer {
+    /// Bind now, so a bad address fails before any work starts
+    pub async fn bind(listen: SocketAddr, resolver: DnsResolver) -> Result<Self> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} (metrics)", listen))?;
+        Ok(Self { listener, resolver })
+    }
+
+    /// Answer scrapes until the process ends; failed accepts are logged and retried
+    pub async fn run(self) -> Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = server::accept(&server.listener, "Metrics").await;
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let server = Arc::clone(&server);
+                    async move { Ok::<_, Infallible>(server.handle(request)) }
+                });
+                let _ = Http::new().serve_connection(stream, service).await;
+            });
+        }
+    }
+
+    fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if request.uri().path() != "/metrics" {
+            return status(StatusCode::NOT_FOUND);
+        }
+        if request.method() != Method::GET {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(
+                self.resolver.metrics().render(self.resolver.cache()),
+            ))
+            .unwrap()
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,198 @@
+use crate::message;
+use anyhow::{Context, Result};
+use colored::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use trust_dns_proto::rr::rdata::{A, AAAA, CNAME, TXT};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType as DnsRecordType};
+
+/// One pinned record; without its own TTL it gets the table's default
+struct StaticRecord {
+    ttl: Option<u32>,
+    data: RData,
+}
+
+/// Names pinned to fixed answers, consulted before the cache and any transport.
+///
+/// A pinned name never goes upstream: record types it has no entry for get an
+/// empty answer, so an IPv4-only pin is not undermined by an upstream AAAA record.
+pub struct Overrides {
+    ttl: u32,
+    records: HashMap<String, Vec<StaticRecord>>,
+}
+
+impl Overrides {
+    pub fn new(ttl: u32) -> Self {
+        Self {
+            ttl,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Add the entries of a file in /etc/hosts format (`IP name [alias...]`) or
+    /// zone-like format (`name [TTL] [IN] A|AAAA|CNAME|TXT value`), mixed freely.
+    ///
+    /// Hosts lines whose address cannot be served, such as the scoped `fe80::1%lo0`
+    /// in the macOS /etc/hosts, are skipped with a warning rather than failing the file.
+    pub fn load(&mut self, path: &Path) -> Result<usize> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read static records {}", path.display()))?;
+
+        let mut loaded = 0;
+        for (i, line) in text.lines().enumerate() {
+            let entries = match parse_line(line) {
+                Ok(entries) => entries,
+                Err(e) if is_hosts_line(line) => {
+                    eprintln!(
+                        "{} {}: line {}: skipped: {:#}",
+                        "Warning:".yellow().bold(),
+                        path.display(),
+                        i + 1,
+                        e
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e.context(format!("{}: line {}", path.display(), i + 1)));
+                }
+            };
+            for (name, record) in entries {
+                self.records.entry(name).or_default().push(record);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// A synthesized wire-format response if `hostname` is pinned, following CNAMEs
+    /// within the table
+    pub fn answer(&self, hostname: &str, type_code: u16) -> Option<Result<Vec<u8>>> {
+        let record_type = DnsRecordType::from(type_code);
+        let mut name = normalise(hostname);
+        let mut answers = Vec::new();
+
+        // Bounded, so a CNAME loop in the table cannot hang a query
+        for _ in 0..8 {
+            let Some(records) = self.records.get(&name) else {
+                break;
+            };
+            let owner = match Name::from_ascii(&name) {
+                Ok(owner) => owner,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            let matching: Vec<&StaticRecord> = records
+                .iter()
+                .filter(|r| r.data.record_type() == record_type)
+                .collect();
+            let cname = records
+                .iter()
+                .find(|r| r.data.record_type() == DnsRecordType::CNAME);
+
+            match (matching.is_empty(), cname) {
+                (false, _) => {
+                    for record in matching {
+                        answers.push(self.record(&owner, record));
+                    }
+                    break;
+                }
+                (true, Some(record)) => {
+                    answers.push(self.record(&owner, record));
+                    let RData::CNAME(target) = &record.data else {
+                        break;
+                    };
+                    name = normalise(&target.0.to_ascii());
+                }
+                (true, None) => break,
+            }
+        }
+
+        if answers.is_empty() && !self.records.contains_key(&normalise(hostname)) {
+            return None;
+        }
+        Some(message::synthesize(hostname, type_code, answers))
+    }
+
+    fn record(&self, owner: &Name, record: &StaticRecord) -> Record {
+        Record::from_rdata(
+            owner.clone(),
+            record.ttl.unwrap_or(self.ttl),
+            record.data.clone(),
+        )
+    }
+}
+
+fn normalise(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether a line starts with something only an address contains; names never
+/// have a colon, so this catches IPv6 addresses `IpAddr` cannot parse
+fn is_hosts_line(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|first| first.contains(':'))
+}
+
+/// Parse one line into the records it pins; blank lines and `#` comments pin nothing
+fn parse_line(line: &str) -> Result<Vec<(String, StaticRecord)>> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    let mut tokens = line.split_whitespace();
+    let Some(first) = tokens.next() else {
+        return Ok(Vec::new());
+    };
+
+    // hosts format: the address comes first, then every name it answers for
+    if let Ok(ip) = first.parse::<IpAddr>() {
+        let data = match ip {
+            IpAddr::V4(ip) => RData::A(A(ip)),
+            IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+        };
+        return Ok(tokens
+            .map(|name| {
+                let record = StaticRecord {
+                    ttl: None,
+                    data: data.clone(),
+                };
+                (normalise(name), record)
+            })
+            .collect());
+    }
+
+    if is_hosts_line(line) {
+        anyhow::bail!("Unusable address {}", first);
+    }
+
+    // zone format: name [TTL] [IN] TYPE value
+    let mut rest: Vec<&str> = tokens.collect();
+    let ttl = match rest.first().map(|t| t.parse::<u32>()) {
+        Some(Ok(ttl)) => {
+            rest.remove(0);
+            Some(ttl)
+        }
+        _ => None,
+    };
+    if rest.first().is_some_and(|t| t.eq_ignore_ascii_case("IN")) {
+        rest.remove(0);
+    }
+    let (Some(kind), Some(_)) = (rest.first(), rest.get(1)) else {
+        anyhow::bail!("Expected `IP name...` or `name [TTL] [IN] TYPE value`");
+    };
+    let value = rest[1..].join(" ");
+
+    let data = match kind.to_ascii_uppercase().as_str() {
+        "A" => RData::A(A(value.parse().context("Invalid IPv4 address")?)),
+        "AAAA" => RData::AAAA(AAAA(value.parse().context("Invalid IPv6 address")?)),
+        "CNAME" => RData::CNAME(CNAME(
+            Name::from_ascii(&value).context("Invalid CNAME target")?,
+        )),
+        "TXT" => RData::TXT(TXT::new(vec![value.trim_matches('"').to_string()])),
+        other => anyhow::bail!("Unsupported record type {}", other),
+    };
+    Ok(vec![(normalise(first), StaticRecord { ttl, data })])
+}
@@ -2,81 +2,84 @@ use crate::Provider;
 
 #[derive(Debug, Clone)]
 pub struct DnsProviderConfig {
-    pub name: &'static str,
+    pub name: String,
     // DoH (HTTP/2) settings
-    pub doh_url: &'static str,
+    pub doh_url: String,
     // DoT settings
-    pub dot_host: &'static str,
+    pub dot_host: String,
     pub dot_port: u16,
-    pub dot_hostname: &'static str,
+    pub dot_hostname: String,
     // DoH3 (HTTP/3) settings
-    pub doh3_url: &'static str,
-    pub doh3_host: &'static str,
+    pub doh3_url: String,
+    pub doh3_host: String,
     pub doh3_port: u16,
-    pub doh3_hostname: &'static str,
+    pub doh3_hostname: String,
 }
 
 impl DnsProviderConfig {
-    pub fn from_provider(provider: &Provider) -> Self {
-        match provider {
+    /// Endpoints of a built-in provider; custom upstreams come from the routing rules
+    pub fn from_provider(provider: &Provider) -> Option<Self> {
+        let config = match provider {
             Provider::Cloudflare => DnsProviderConfig {
-                name: "Cloudflare",
-                doh_url: "https://cloudflare-dns.com/dns-query",
-                dot_host: "1.1.1.1",
+                name: "Cloudflare".into(),
+                doh_url: "https://cloudflare-dns.com/dns-query".into(),
+                dot_host: "1.1.1.1".into(),
                 dot_port: 853,
-                dot_hostname: "cloudflare-dns.com",
-                doh3_url: "https://cloudflare-dns.com/dns-query",
-                doh3_host: "1.1.1.1",
+                dot_hostname: "cloudflare-dns.com".into(),
+                doh3_url: "https://cloudflare-dns.com/dns-query".into(),
+                doh3_host: "1.1.1.1".into(),
                 doh3_port: 443,
-                doh3_hostname: "cloudflare-dns.com",
+                doh3_hostname: "cloudflare-dns.com".into(),
             },
             Provider::Google => DnsProviderConfig {
-                name: "Google",
-                doh_url: "https://dns.google/dns-query",
-                dot_host: "8.8.8.8",
+                name: "Google".into(),
+                doh_url: "https://dns.google/dns-query".into(),
+                dot_host: "8.8.8.8".into(),
                 dot_port: 853,
-                dot_hostname: "dns.google",
-                doh3_url: "https://dns.google/dns-query",
-                doh3_host: "8.8.8.8",
+                dot_hostname: "dns.google".into(),
+                doh3_url: "https://dns.google/dns-query".into(),
+                doh3_host: "8.8.8.8".into(),
                 doh3_port: 443,
-                doh3_hostname: "dns.google",
+                doh3_hostname: "dns.google".into(),
             },
             Provider::Quad9 => DnsProviderConfig {
-                name: "Quad9",
-                doh_url: "https://dns.quad9.net/dns-query",
-                dot_host: "9.9.9.9",
+                name: "Quad9".into(),
+                doh_url: "https://dns.quad9.net/dns-query".into(),
+                dot_host: "9.9.9.9".into(),
                 dot_port: 853,
-                dot_hostname: "dns.quad9.net",
-                doh3_url: "https://dns.quad9.net/dns-query",
-                doh3_host: "9.9.9.9",
+                dot_hostname: "dns.quad9.net".into(),
+                doh3_url: "https://dns.quad9.net/dns-query".into(),
+                doh3_host: "9.9.9.9".into(),
                 doh3_port: 443,
-                doh3_hostname: "dns.quad9.net",
+                doh3_hostname: "dns.quad9.net".into(),
             },
             Provider::NextDns => DnsProviderConfig {
-                name: "NextDNS",
-                doh_url: "https://dns.nextdns.io/dns-query",
-                dot_host: "45.90.28.0",
+                name: "NextDNS".into(),
+                doh_url: "https://dns.nextdns.io/dns-query".into(),
+                dot_host: "45.90.28.0".into(),
                 dot_port: 853,
-                dot_hostname: "dns.nextdns.io",
-                doh3_url: "https://dns.nextdns.io/dns-query",
-                doh3_host: "45.90.28.0",
+                dot_hostname: "dns.nextdns.io".into(),
+                doh3_url: "https://dns.nextdns.io/dns-query".into(),
+                doh3_host: "45.90.28.0".into(),
                 doh3_port: 443,
-                doh3_hostname: "dns.nextdns.io",
+                doh3_hostname: "dns.nextdns.io".into(),
             },
             Provider::Nord => DnsProviderConfig {
-                name: "Nordsec",
+                name: "Nordsec".into(),
 
-                doh_url: "https://dns1.nordvpn.com/dns-query",
+                doh_url: "https://dns1.nordvpn.com/dns-query".into(),
 
-                dot_host: "103.86.99.112",
+                dot_host: "103.86.99.112".into(),
                 dot_port: 853,
-                dot_hostname: "dns1.nordvpn.com",
+                dot_hostname: "dns1.nordvpn.com".into(),
 
-                doh3_url: "https://dns1.nordvpn.com/dns-query",
-                doh3_host: "103.86.99.112",
+                doh3_url: "https://dns1.nordvpn.com/dns-query".into(),
+                doh3_host: "103.86.99.112".into(),
                 doh3_port: 443,
-                doh3_hostname: "dns1.nordvpn.com",
+                doh3_hostname: "dns1.nordvpn.com".into(),
             },
-        }
+            Provider::Custom(_) => return None,
+        };
+        Some(config)
     }
 }
@@ -0,0 +1,262 @@
+use crate::server::Transport;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use colored::*;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What the query log records about the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ClientPrivacy {
+    /// The client's IP address
+    Full,
+    /// A keyed hash of the address; stable within one run, unlinkable across runs
+    Hash,
+    /// Nothing
+    Drop,
+}
+
+/// When the log file is moved aside and a new one started
+#[derive(Debug, Clone, Default)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.<keep>`
+    pub keep: usize,
+}
+
+/// How a query was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Resolved,
+    Cached,
+    Stale,
+    Static,
+    Blocked,
+    Refused,
+    Failed,
+}
+
+/// What the log records about one answered query
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    pub transport: Transport,
+    pub name: &'a str,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    pub rcode: String,
+    pub latency_ms: f64,
+    pub status: Status,
+}
+
+/// One line of the log: the entry with when and for whom
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+    #[serde(flatten)]
+    entry: &'a Entry<'a>,
+}
+
+/// Append-only JSON-lines audit trail of served queries.
+///
+/// Lines are handed to a writer thread, so a slow disk delays the log and not the
+/// queries being answered.
+pub struct QueryLog {
+    path: PathBuf,
+    privacy: ClientPrivacy,
+    key: RandomState,
+    lines: Sender<Vec<u8>>,
+}
+
+impl QueryLog {
+    /// Append to the log at `path`, creating it if needed
+    pub fn open(path: &Path, rotation: Rotation, privacy: ClientPrivacy) -> Result<Self> {
+        let writer = Writer::open(path, rotation)?;
+        let (lines, queued) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || writer.run(queued))
+            .context("Failed to start the query log writer")?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            privacy,
+            key: RandomState::new(),
+            lines,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The client as it should appear in the log
+    fn client(&self, ip: IpAddr) -> Option<String> {
+        match self.privacy {
+            ClientPrivacy::Full => Some(ip.to_string()),
+            ClientPrivacy::Hash => {
+                let mut hasher = self.key.build_hasher();
+                ip.hash(&mut hasher);
+                Some(format!("{:016x}", hasher.finish()))
+            }
+            ClientPrivacy::Drop => None,
+        }
+    }
+
+    /// Queue the entry for a query from `client`; write errors are reported by the
+    /// writer thread as they happen
+    pub fn write(&self, client: IpAddr, entry: &Entry) -> Result<()> {
+        let line = Line {
+            timestamp: rfc3339(SystemTime::now()),
+            client: self.client(client),
+            entry,
+        };
+        let mut line = serde_json::to_vec(&line)?;
+        line.push(b'\n');
+
+        self.lines
+            .send(line)
+            .map_err(|_| anyhow::anyhow!("Query log writer for {} stopped", self.path.display()))
+    }
+}
+
+/// The open log file and when it was started, owned by the writer thread
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl Writer {
+    fn open(path: &Path, rotation: Rotation) -> Result<Self> {
+        let (file, size) = open_file(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    /// Write lines until every sender is gone, flushing whenever the queue runs dry
+    fn run(mut self, lines: Receiver<Vec<u8>>) {
+        while let Ok(line) = lines.recv() {
+            let mut result = self.append(&line);
+            while result.is_ok() {
+                match lines.try_recv() {
+                    Ok(line) => result = self.append(&line),
+                    Err(_) => break,
+                }
+            }
+            let result = result.and_then(|_| {
+                self.file
+                    .flush()
+                    .with_context(|| format!("Failed to write query log {}", self.path.display()))
+            });
+            if let Err(e) = result {
+                eprintln!("{} {:#}", "Warning:".yellow().bold(), e);
+            }
+        }
+        let _ = self.file.flush();
+    }
+
+    /// Append one line, rotating first if the file is due
+    fn append(&mut self, line: &[u8]) -> Result<()> {
+        let too_big = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max| self.opened.elapsed() >= max);
+        if too_big || too_old {
+            self.file.flush()?;
+            self.rotate()?;
+            (self.file, self.size) = open_file(&self.path)?;
+            self.opened = Instant::now();
+        }
+
+        self.file
+            .write_all(line)
+            .with_context(|| format!("Failed to write query log {}", self.path.display()))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, and move the log to `<path>.1`
+    fn rotate(&self) -> Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.rotation.keep == 0 {
+            return std::fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to remove {}", self.path.display()));
+        }
+        let _ = std::fs::remove_file(rotated(self.rotation.keep));
+        for n in (1..self.rotation.keep).rev() {
+            let _ = std::fs::rename(rotated(n), rotated(n + 1));
+        }
+        std::fs::rename(&self.path, rotated(1))
+            .with_context(|| format!("Failed to rotate {}", self.path.display()))
+    }
+}
+
+fn open_file(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open query log {}", path.display()))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok((BufWriter::new(file), size))
+}
+
+/// UTC timestamp with milliseconds, e.g. `2024-05-01T12:00:00.250Z`
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
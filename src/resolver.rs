@@ -1,282 +1,985 @@
+use crate::blocklist::Blocklist;
+use crate::cache::{CachePolicy, DnsCache};
+use crate::candidates::{CandidateContext, LiveCandidate, Validator};
+use crate::consensus::Consensus;
 use crate::doh::DohResolver;
 use crate::doh3::Doh3Resolver;
 use crate::dot::DotResolver;
+use crate::error::{classify, DnsError, SharedError};
+use crate::fallback::ProtocolChain;
+use crate::hedge::HedgePolicy;
+use crate::input::Query;
+use crate::limits::{Limiter, Limits};
+use crate::message;
+use crate::metrics::Metrics;
+use crate::overrides::Overrides;
 use crate::providers::DnsProviderConfig;
+use crate::retry::RetryPolicy;
+use crate::routing::{Route, Router};
+use crate::singleflight::Group;
+use crate::stats::StatsTracker;
+use crate::timeout::Timeouts;
 use crate::{Protocol, Provider, RecordType};
 use anyhow::Result;
-use futures::future::select_ok;
-use std::pin::Pin;
-use std::sync::Arc;
+use futures::future::{select_ok, BoxFuture};
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use futures::FutureExt;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
+use tracing::{debug, debug_span, info, instrument, Instrument};
+use trust_dns_proto::op::ResponseCode;
 
+/// A successful lookup together with how it was obtained
+#[derive(Debug, Clone)]
+pub struct Resolved<T> {
+    pub data: T,
+    pub provider: Provider,
+    pub protocol: Protocol,
+    pub elapsed: Duration,
+    /// Number of attempts the retry policy needed (1 = first try, 0 = from cache)
+    pub attempts: u32,
+    /// Answered from the cache instead of the network
+    pub cached: bool,
+    /// Served from an expired cache entry because every upstream failed
+    pub stale: bool,
+    /// Synthesized from the static records instead of asked upstream
+    pub local: bool,
+}
+
+/// What a lookup produces: parsed records or the raw RDATA of the first answer
+pub trait Answer: Sized + Send + 'static {
+    /// Decode a wire-format response
+    fn parse(response: &[u8], hostname: &str) -> Result<Self>;
+
+    /// Short description for log events
+    fn summary(&self) -> String;
+}
+
+impl Answer for Vec<String> {
+    fn parse(response: &[u8], hostname: &str) -> Result<Self> {
+        let result = message::parse_records(response);
+
+        match &result {
+            Ok(records) => debug!(hostname, ?records, "Parsed {} record(s)", records.len()),
+            Err(e) => debug!(hostname, error = %e, "Failed to parse response"),
+        }
+
+        result
+    }
+
+    fn summary(&self) -> String {
+        format!("{} records", self.len())
+    }
+}
+
+impl Answer for Vec<u8> {
+    fn parse(response: &[u8], _hostname: &str) -> Result<Self> {
+        message::extract_raw_rdata(response)
+    }
+
+    fn summary(&self) -> String {
+        format!("{} bytes", self.len())
+    }
+}
+
+/// A complete wire-format response, passed on exactly as the provider sent it
+#[derive(Debug, Clone)]
+pub struct Wire(pub Vec<u8>);
+
+impl Answer for Wire {
+    fn parse(response: &[u8], _hostname: &str) -> Result<Self> {
+        // NXDOMAIN and NODATA are answers; a failing server is left to the next provider
+        match message::response_code(response)? {
+            code @ (ResponseCode::ServFail | ResponseCode::Refused) => {
+                anyhow::bail!("Server answered {}", code)
+            }
+            _ => Ok(Wire(response.to_vec())),
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!("{} bytes", self.0.len())
+    }
+}
+
+/// How each hostname of a streamed or expanded batch is resolved, mirroring the batch modes
+#[derive(Debug, Clone)]
+pub enum Strategy {
+    /// One provider, falling back along the protocol chain
+    Provider(Provider),
+    /// All providers at once, first answer wins
+    Race,
+    /// One provider over every protocol at once, first answer wins
+    ProtocolRace(Provider),
+    /// Provider with the best measured latency and success history
+    Fastest,
+    /// Preferred provider first, a second one only when it is slow
+    Hedged(Provider, HedgePolicy),
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::Provider(provider) => write!(f, "{:?}", provider),
+            Strategy::Race => write!(f, "race, all providers"),
+            Strategy::ProtocolRace(provider) => write!(f, "protocol race, {:?}", provider),
+            Strategy::Fastest => write!(f, "fastest known provider"),
+            Strategy::Hedged(provider, policy) => match &policy.secondary {
+                Some(secondary) => write!(f, "hedged, {:?} then {:?}", provider, secondary),
+                None => write!(f, "hedged, {:?} then the best other provider", provider),
+            },
+        }
+    }
+}
+
+/// Identifies an upstream query for coalescing: name, record type, provider, protocol
+type FlightKey = (String, u16, Provider, Protocol);
+
+/// An upstream response and the attempts it took, handed to every caller of a
+/// coalesced query
+type Flight = std::result::Result<(Vec<u8>, u32), SharedError>;
+
+/// What a reload replaces: the routing rules together with the `[upstream.*]`
+/// definitions they forward to, the static records and the block lists
+#[derive(Default, Clone)]
+pub struct Config {
+    pub router: Option<Arc<Router>>,
+    pub overrides: Option<Arc<Overrides>>,
+    /// Only consulted by `serve`
+    pub blocklist: Option<Arc<Blocklist>>,
+}
+
+/// Cheap to clone: every field is shared, so spawned tasks get their own handle
+#[derive(Clone)]
 pub struct DnsResolver {
     doh: Arc<DohResolver>,
     dot: Arc<DotResolver>,
     doh3: Arc<Doh3Resolver>,
+    deadline: Option<Duration>,
+    retry: Arc<RetryPolicy>,
+    stats: Arc<StatsTracker>,
+    limiter: Arc<Limiter>,
+    cache: Option<Arc<DnsCache>>,
+    inflight: Arc<Group<FlightKey, Flight>>,
+    /// Swapped as a whole on reload, so a query sees either the old configuration or the new
+    config: Arc<RwLock<Arc<Config>>>,
+    metrics: Arc<Metrics>,
+    /// Only take cached answers given by the provider being asked, not another one
+    own_answers: bool,
 }
 
 impl DnsResolver {
-    pub fn new() -> Self {
+    pub fn new(timeouts: Timeouts) -> Self {
+        let metrics = Arc::new(Metrics::default());
         Self {
-            doh: Arc::new(DohResolver::new()),
-            dot: Arc::new(DotResolver::new()),
-            doh3: Arc::new(Doh3Resolver::new()),
+            doh: Arc::new(DohResolver::new(timeouts, Arc::clone(&metrics))),
+            dot: Arc::new(DotResolver::new(timeouts, Arc::clone(&metrics))),
+            doh3: Arc::new(Doh3Resolver::new(timeouts, Arc::clone(&metrics))),
+            deadline: None,
+            retry: Arc::new(RetryPolicy::default()),
+            stats: Arc::new(StatsTracker::new(20)),
+            limiter: Arc::new(Limiter::new(Limits::default())),
+            cache: None,
+            inflight: Arc::new(Group::default()),
+            config: Arc::default(),
+            metrics,
+            own_answers: false,
+        }
+    }
+
+    /// Bound the total time a batch may take; unfinished hostnames fail with a timeout
+    pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Retry transient failures of any transport according to `policy`
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Arc::new(policy);
+        self
+    }
+
+    /// Track per-provider latency and success history with `tracker`
+    pub fn with_stats(mut self, tracker: StatsTracker) -> Self {
+        self.stats = Arc::new(tracker);
+        self
+    }
+
+    /// Cap concurrency and query rate towards the upstream providers
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limiter = Arc::new(Limiter::new(limits));
+        self
+    }
+
+    /// Answer repeated queries from memory for as long as their TTL allows
+    pub fn with_cache(mut self, policy: Option<CachePolicy>) -> Self {
+        self.cache = policy.map(|policy| Arc::new(DnsCache::new(policy)));
+        self
+    }
+
+    /// Route queries by name to other upstreams, protocols or a refusal before resolving
+    pub fn with_router(self, router: Option<Router>) -> Self {
+        self.update_config(|config| config.router = router.map(Arc::new));
+        self
+    }
+
+    /// Answer pinned names from static records before the cache or any transport
+    pub fn with_overrides(self, overrides: Option<Overrides>) -> Self {
+        self.update_config(|config| config.overrides = overrides.map(Arc::new));
+        self
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// Replace the whole configuration for every query that starts from now on
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /// Change part of the configuration, keeping the rest as it is at that moment
+    pub fn update_config(&self, change: impl FnOnce(&mut Config)) {
+        let mut current = self.config.write().unwrap();
+        let mut config = Config::clone(&current);
+        change(&mut config);
+        *current = Arc::new(config);
+    }
+
+    /// A handle that keeps seeing the current configuration for as long as it lives,
+    /// so one query never mixes rules, records or lists from before and after a reload
+    pub fn pinned(&self) -> Self {
+        let mut pinned = self.clone();
+        pinned.config = Arc::new(RwLock::new(self.config()));
+        pinned
+    }
+
+    /// A handle whose cache hits always come from the provider asked, so comparing
+    /// providers never compares one provider's cached answer with itself
+    fn own_answers(&self) -> Self {
+        let mut own = self.clone();
+        own.own_answers = true;
+        own
+    }
+
+    fn router(&self) -> Option<Arc<Router>> {
+        self.config().router.clone()
+    }
+
+    pub fn stats(&self) -> &StatsTracker {
+        &self.stats
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn cache(&self) -> Option<&DnsCache> {
+        self.cache.as_deref()
+    }
+
+    /// Endpoints of a built-in provider or of a custom upstream from the routing rules
+    fn endpoints(&self, provider: &Provider) -> Result<DnsProviderConfig> {
+        if let Some(config) = DnsProviderConfig::from_provider(provider) {
+            return Ok(config);
         }
+        self.router()
+            .and_then(|router| match provider {
+                Provider::Custom(name) => router.upstream(name).cloned(),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("Unknown upstream '{:?}'", provider))
     }
 
-    /// Resolve all hostnames concurrently using a single provider
-    pub async fn resolve_batch(
+    /// Send one wire-format query over `protocol`
+    async fn exchange(
         &self,
-        hostnames: &[String],
+        protocol: Protocol,
+        config: &DnsProviderConfig,
+        query: &[u8],
+        hostname: &str,
+        type_code: u16,
+    ) -> Result<Vec<u8>> {
+        let _in_flight = self.metrics.in_flight(protocol);
+        match protocol {
+            Protocol::Doh => self.doh.exchange(config, query, hostname, type_code).await,
+            Protocol::Dot => self.dot.exchange(config, query, hostname, type_code).await,
+            Protocol::Doh3 => self.doh3.exchange(config, query, hostname, type_code).await,
+        }
+    }
+
+    /// Resolve one hostname with one provider, answering from the cache when possible
+    /// and otherwise sharing or starting the upstream query
+    #[instrument(
+        name = "query",
+        skip_all,
+        fields(
+            hostname = %hostname,
+            record_type = %crate::RecordType::from_code(type_code),
+            ?provider,
+            ?protocol
+        )
+    )]
+    async fn resolve_one<T: Answer>(
+        &self,
+        hostname: &str,
         provider: &Provider,
         protocol: &Protocol,
-        record_type: &RecordType,
-        verbose: bool,
-    ) -> Vec<Result<Vec<String>>> {
-        let config = DnsProviderConfig::from_provider(provider);
-        let type_code = record_type.to_type_code();
-
-        let mut handles: Vec<JoinHandle<Result<Vec<String>>>> = Vec::new();
-
-        // Send all queries concurrently
-        for hostname in hostnames {
-            let hostname = hostname.clone();
-            let config = config.clone();
-            let doh = Arc::clone(&self.doh);
-            let dot = Arc::clone(&self.dot);
-            let doh3 = Arc::clone(&self.doh3);
-            let protocol = protocol.clone();
-
-            let handle = tokio::spawn(async move {
-                match protocol {
-                    Protocol::Doh => doh.resolve(&hostname, &config, type_code, verbose).await,
-                    Protocol::Dot => dot.resolve(&hostname, &config, type_code, verbose).await,
-                    Protocol::Doh3 => doh3.resolve(&hostname, &config, type_code, verbose).await,
-                }
+        type_code: u16,
+    ) -> Result<Resolved<T>> {
+        let start = Instant::now();
+        let overrides = self.config().overrides.clone();
+        if let Some(answer) = overrides.and_then(|overrides| overrides.answer(hostname, type_code))
+        {
+            info!("Answered from the static records");
+            return T::parse(&answer?, hostname).map(|data| Resolved {
+                data,
+                provider: provider.clone(),
+                protocol: *protocol,
+                elapsed: start.elapsed(),
+                attempts: 0,
+                cached: false,
+                stale: false,
+                local: true,
             });
+        }
+        if let Some(cache) = &self.cache {
+            let hit = cache
+                .get(&cache.key(hostname, type_code, provider))
+                .filter(|hit| !self.own_answers || hit.provider == *provider);
+            if let Some(hit) = hit {
+                info!(
+                    negative = hit.negative,
+                    answered_by = ?hit.provider,
+                    answered_over = ?hit.protocol,
+                    ttl_left = hit.ttl_left.as_secs(),
+                    "Cache hit"
+                );
+                return T::parse(&hit.relayed()?, hostname).map(|data| Resolved {
+                    data,
+                    provider: hit.provider,
+                    protocol: hit.protocol,
+                    elapsed: start.elapsed(),
+                    attempts: 0,
+                    cached: true,
+                    stale: false,
+                    local: false,
+                });
+            }
+        }
 
-            handles.push(handle);
+        // Identical queries already on their way upstream are joined, not repeated
+        let flight = (
+            hostname.trim_end_matches('.').to_ascii_lowercase(),
+            type_code,
+            provider.clone(),
+            *protocol,
+        );
+        let fetch = {
+            let resolver = self.clone();
+            let (hostname, provider, protocol) =
+                (hostname.to_string(), provider.clone(), *protocol);
+            async move {
+                resolver
+                    .fetch(&hostname, &provider, protocol, type_code)
+                    .await
+                    .map_err(SharedError::new)
+            }
+        };
+        let (result, joined) = self.inflight.run(flight, fetch).await;
+
+        if joined {
+            info!("Shared the query already in flight");
         }
 
-        // Collect all results
-        let mut results = Vec::new();
-        for handle in handles {
-            let result = handle
-                .await
-                .unwrap_or_else(|e| Err(anyhow::anyhow!("Task failed: {}", e)));
-            results.push(result);
+        let (response, attempts) = result?;
+        T::parse(&response, hostname).map(|data| Resolved {
+            data,
+            provider: provider.clone(),
+            protocol: *protocol,
+            elapsed: start.elapsed(),
+            attempts,
+            cached: false,
+            stale: false,
+            local: false,
+        })
+    }
+
+    /// Query one provider over one protocol under the retry policy, recording
+    /// stats and caching the response
+    async fn fetch(
+        &self,
+        hostname: &str,
+        provider: &Provider,
+        protocol: Protocol,
+        type_code: u16,
+    ) -> Result<(Vec<u8>, u32)> {
+        let config = self.endpoints(provider)?;
+        let label = format!("{} via {:?}/{:?}", hostname, provider, protocol);
+
+        let (result, attempts) = self
+            .retry
+            .run(&label, || async {
+                let query = message::build_query(hostname, type_code)?;
+                let span = debug_span!("exchange", id = message::id(&query));
+                let _permit = self
+                    .limiter
+                    .acquire(provider)
+                    .instrument(span.clone())
+                    .await;
+                // Timed without the permit wait or retry backoff around it
+                let start = Instant::now();
+                let result = self
+                    .exchange(protocol, &config, &query, hostname, type_code)
+                    .instrument(span)
+                    .await;
+                self.stats.record(
+                    provider,
+                    protocol,
+                    result.as_ref().ok().map(|_| start.elapsed()),
+                );
+                let rcode = result.as_ref().ok().and_then(|response| {
+                    message::response_code(response)
+                        .ok()
+                        .map(|code| format!("{:?}", code).to_uppercase())
+                });
+                self.metrics.query(provider, protocol, type_code, rcode);
+                result
+            })
+            .await;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) if attempts > 1 => {
+                let message = format!("{} (after {} attempts)", e, attempts);
+                return Err(e.context(message));
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(cache) = &self.cache {
+            let key = cache.key(hostname, type_code, provider);
+            if let Some(ttl) = cache.insert(key, &response, provider, protocol) {
+                info!(ttl = ttl.as_secs(), "Stored in the cache");
+            }
         }
 
-        results
+        Ok((response, attempts))
     }
 
-    /// Resolve batch and return raw record data (for ECH parsing)
-    pub async fn resolve_batch_raw(
+    /// Resolve one hostname with one provider, falling back along the protocol chain
+    /// whenever a protocol fails at the transport level
+    async fn resolve_chain<T: Answer>(
         &self,
-        hostnames: &[String],
+        hostname: &str,
         provider: &Provider,
-        protocol: &Protocol,
+        chain: &ProtocolChain,
         type_code: u16,
-        verbose: bool,
-    ) -> Vec<Result<Vec<u8>>> {
-        let config = DnsProviderConfig::from_provider(provider);
-
-        let mut handles: Vec<JoinHandle<Result<Vec<u8>>>> = Vec::new();
-
-        for hostname in hostnames {
-            let hostname = hostname.clone();
-            let config = config.clone();
-            let doh = Arc::clone(&self.doh);
-            let dot = Arc::clone(&self.dot);
-            let doh3 = Arc::clone(&self.doh3);
-            let protocol = protocol.clone();
-
-            let handle = tokio::spawn(async move {
-                match protocol {
-                    Protocol::Doh => {
-                        doh.resolve_raw(&hostname, &config, type_code, verbose)
-                            .await
-                    }
-                    Protocol::Dot => {
-                        dot.resolve_raw(&hostname, &config, type_code, verbose)
-                            .await
-                    }
-                    Protocol::Doh3 => {
-                        doh3.resolve_raw(&hostname, &config, type_code, verbose)
-                            .await
+    ) -> Result<Resolved<T>> {
+        let candidates = chain.candidates(provider);
+        let mut last_err = None;
+
+        for (i, protocol) in candidates.iter().enumerate() {
+            match self
+                .resolve_one::<T>(hostname, provider, protocol, type_code)
+                .await
+            {
+                Ok(resolved) => {
+                    chain.learn(provider, *protocol);
+                    return Ok(resolved);
+                }
+                Err(e) => {
+                    let class = classify(&e);
+                    let next = candidates.get(i + 1);
+                    match next {
+                        Some(next) if class.is_transport() => {
+                            info!(
+                                hostname,
+                                ?provider,
+                                ?protocol,
+                                class = %class,
+                                error = %e,
+                                "Falling back to {:?}",
+                                next
+                            );
+                            last_err = Some(e);
+                        }
+                        _ => return Err(e),
                     }
                 }
-            });
+            }
+        }
 
-            handles.push(handle);
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No protocols configured")))
+    }
+
+    /// Serve stale (RFC 8767): when resolution failed because no upstream could be
+    /// reached, answer from a cached entry that is still within its stale window.
+    ///
+    /// Every lookup by hostname ends here, so this is also where it counts as one
+    /// cache hit or miss, however many providers the strategy consulted.
+    fn or_stale<T: Answer>(
+        &self,
+        hostname: &str,
+        type_code: u16,
+        result: Result<Resolved<T>>,
+    ) -> Result<Resolved<T>> {
+        let local = matches!(&result, Ok(resolved) if resolved.local);
+        if self.cache.is_some() && !local {
+            let hit = matches!(&result, Ok(resolved) if resolved.cached);
+            self.metrics.cache_lookup(hit);
         }
 
-        let mut results = Vec::new();
+        let e = match result {
+            Ok(resolved) => return Ok(resolved),
+            Err(e) => e,
+        };
+
+        let hit = match &self.cache {
+            Some(cache) if classify(&e).is_transport() => cache.stale(hostname, type_code),
+            _ => None,
+        };
+        let Some(hit) = hit else {
+            return Err(e);
+        };
+
+        info!(
+            hostname,
+            error = %e,
+            stale = hit.stale,
+            answered_by = ?hit.provider,
+            answered_over = ?hit.protocol,
+            "Resolution failed, serving the cached answer"
+        );
+
+        T::parse(&hit.relayed()?, hostname).map(|data| Resolved {
+            data,
+            provider: hit.provider,
+            protocol: hit.protocol,
+            elapsed: Duration::ZERO,
+            attempts: 0,
+            cached: true,
+            stale: hit.stale,
+            local: false,
+        })
+    }
+
+    /// Spawn one task per hostname and collect the results in input order
+    async fn spawn_batch<T, F, Fut>(&self, hostnames: &[String], lookup: F) -> Vec<Result<T>>
+    where
+        T: Send + 'static,
+        F: Fn(DnsResolver, String) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        let started = Instant::now();
+
+        // Send all queries concurrently
+        let handles: Vec<JoinHandle<Result<T>>> = hostnames
+            .iter()
+            .map(|hostname| tokio::spawn(lookup(self.clone(), hostname.clone())))
+            .collect();
+
+        self.collect(handles, started).await
+    }
+
+    /// Await spawned lookups in order, aborting whatever is still running at the deadline
+    async fn collect<T>(
+        &self,
+        handles: Vec<JoinHandle<Result<T>>>,
+        started: Instant,
+    ) -> Vec<Result<T>> {
+        let mut results = Vec::with_capacity(handles.len());
         for handle in handles {
-            let result = handle
-                .await
-                .unwrap_or_else(|e| Err(anyhow::anyhow!("Task failed: {}", e)));
-            results.push(result);
+            results.push(self.join(handle, started).await);
         }
-
         results
     }
 
-    /// Race mode: resolve each hostname by racing all providers simultaneously
-    /// Returns the result from whichever provider responds first
-    pub async fn resolve_batch_race(
+    /// Await one spawned lookup, aborting it if the batch deadline passes first
+    async fn join<T>(&self, mut handle: JoinHandle<Result<T>>, started: Instant) -> Result<T> {
+        let joined = match self.deadline {
+            Some(limit) => {
+                let at = tokio::time::Instant::from_std(started + limit);
+                match tokio::time::timeout_at(at, &mut handle).await {
+                    Ok(joined) => joined,
+                    Err(_) => {
+                        handle.abort();
+                        return Err(DnsError::Deadline(limit).into());
+                    }
+                }
+            }
+            None => handle.await,
+        };
+        joined.unwrap_or_else(|e| Err(anyhow::anyhow!("Task failed: {}", e)))
+    }
+
+    /// Resolve queries as they are read, at most `concurrency` at a time, yielding
+    /// each result as soon as it completes (so not necessarily in input order)
+    pub fn resolve_stream<S>(
+        &self,
+        queries: S,
+        protocols: &[Protocol],
+        strategy: Strategy,
+        concurrency: usize,
+    ) -> impl Stream<Item = (Query, Result<Resolved<Vec<String>>>)>
+    where
+        S: Stream<Item = Query>,
+    {
+        let started = Instant::now();
+        let chain = Arc::new(ProtocolChain::new(protocols));
+        let protocols: Arc<[Protocol]> = protocols.into();
+        let strategy = Arc::new(strategy);
+        let resolver = self.clone();
+
+        queries
+            .map(move |query| {
+                let lookup = resolver.clone();
+                let chain = Arc::clone(&chain);
+                let protocols = Arc::clone(&protocols);
+                let strategy = Arc::clone(&strategy);
+                let hostname = query.hostname.clone();
+                let type_code = query.record_type.to_type_code();
+
+                let handle = tokio::spawn(async move {
+                    lookup
+                        .resolve_with(&hostname, &strategy, &chain, &protocols, type_code)
+                        .await
+                });
+
+                let resolver = resolver.clone();
+                async move { (query, resolver.join(handle, started).await) }
+            })
+            .buffer_unordered(concurrency.max(1))
+    }
+
+    /// Resolve all hostnames concurrently the way `strategy` prescribes
+    pub async fn resolve_batch_strategy(
         &self,
         hostnames: &[String],
-        protocol: &Protocol,
+        protocols: &[Protocol],
+        strategy: Strategy,
         record_type: &RecordType,
-        verbose: bool,
-    ) -> Vec<Result<(Vec<String>, Provider, Duration)>> {
+    ) -> Vec<Result<Resolved<Vec<String>>>> {
         let type_code = record_type.to_type_code();
+        self.resolve_batch_as(hostnames, protocols, strategy, type_code)
+            .await
+    }
 
-        let mut handles: Vec<JoinHandle<Result<(Vec<String>, Provider, Duration)>>> = Vec::new();
+    /// Like `resolve_batch_strategy`, returning raw record data (for ECH parsing)
+    pub async fn resolve_batch_raw(
+        &self,
+        hostnames: &[String],
+        protocols: &[Protocol],
+        strategy: Strategy,
+        type_code: u16,
+    ) -> Vec<Result<Resolved<Vec<u8>>>> {
+        self.resolve_batch_as(hostnames, protocols, strategy, type_code)
+            .await
+    }
 
-        for hostname in hostnames {
-            let hostname = hostname.clone();
-            let doh = Arc::clone(&self.doh);
-            let dot = Arc::clone(&self.dot);
-            let doh3 = Arc::clone(&self.doh3);
-            let protocol = protocol.clone();
+    async fn resolve_batch_as<T: Answer>(
+        &self,
+        hostnames: &[String],
+        protocols: &[Protocol],
+        strategy: Strategy,
+        type_code: u16,
+    ) -> Vec<Result<Resolved<T>>> {
+        let chain = Arc::new(ProtocolChain::new(protocols));
+        let protocols: Arc<[Protocol]> = protocols.into();
+        let strategy = Arc::new(strategy);
 
-            let handle = tokio::spawn(async move {
-                Self::race_providers(hostname, doh, dot, doh3, protocol, type_code, verbose).await
-            });
+        self.spawn_batch(hostnames, |resolver, hostname| {
+            let chain = Arc::clone(&chain);
+            let protocols = Arc::clone(&protocols);
+            let strategy = Arc::clone(&strategy);
+            async move {
+                resolver
+                    .resolve_with(&hostname, &strategy, &chain, &protocols, type_code)
+                    .await
+            }
+        })
+        .await
+    }
 
-            handles.push(handle);
-        }
+    /// Resolve a single hostname the way `strategy` prescribes
+    pub async fn resolve_strategy(
+        &self,
+        hostname: &str,
+        protocols: &[Protocol],
+        strategy: &Strategy,
+        record_type: &RecordType,
+    ) -> Result<Resolved<Vec<String>>> {
+        let chain = ProtocolChain::new(protocols);
+        let type_code = record_type.to_type_code();
+        self.resolve_with(hostname, strategy, &chain, protocols, type_code)
+            .await
+    }
 
-        let mut results = Vec::new();
-        for handle in handles {
-            let result = handle
+    /// Resolve one query by `strategy` and return the whole upstream response, for
+    /// forwarding to clients
+    pub async fn resolve_message(
+        &self,
+        hostname: &str,
+        protocols: &[Protocol],
+        strategy: &Strategy,
+        type_code: u16,
+    ) -> Result<Resolved<Wire>> {
+        let chain = ProtocolChain::new(protocols);
+        self.resolve_with(hostname, strategy, &chain, protocols, type_code)
+            .await
+    }
+
+    /// Find the first candidate in list order that resolves and passes every validator.
+    ///
+    /// Candidates are walked one at a time, or with `race` all queried at once while
+    /// earlier candidates still take precedence. Either way, outstanding queries are
+    /// cancelled as soon as the winner is confirmed.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn resolve_first_live(
+        &self,
+        candidates: &[String],
+        protocols: &[Protocol],
+        strategy: &Strategy,
+        record_type: &RecordType,
+        validators: &[Box<dyn Validator>],
+        race: bool,
+    ) -> Result<LiveCandidate> {
+        let context = CandidateContext {
+            resolver: self,
+            protocols,
+            strategy,
+        };
+        let chain = ProtocolChain::new(protocols);
+        let type_code = record_type.to_type_code();
+        let check = |i: usize| {
+            self.check_candidate(&context, &chain, &candidates[i], validators, type_code)
+        };
+
+        let search = async {
+            // Outcome per candidate, filled in as checks finish
+            let mut outcomes: Vec<Option<Result<Resolved<Vec<String>>>>> =
+                candidates.iter().map(|_| None).collect();
+
+            if race {
+                let mut pending: FuturesUnordered<_> = (0..candidates.len())
+                    .map(|i| check(i).map(move |result| (i, result)))
+                    .collect();
+
+                while let Some((i, result)) = pending.next().await {
+                    outcomes[i] = Some(result);
+                    // The winner is the first success with every earlier candidate rejected
+                    let decided = outcomes
+                        .iter()
+                        .position(|outcome| !matches!(outcome, Some(Err(_))));
+                    if let Some(i) = decided {
+                        if matches!(outcomes[i], Some(Ok(_))) {
+                            break;
+                        }
+                    }
+                }
+            } else {
+                for (i, outcome) in outcomes.iter_mut().enumerate() {
+                    let result = check(i).await;
+                    let found = result.is_ok();
+                    *outcome = Some(result);
+                    if found {
+                        break;
+                    }
+                }
+            }
+
+            let mut rejected = Vec::new();
+            for (candidate, outcome) in candidates.iter().zip(outcomes) {
+                match outcome {
+                    Some(Ok(resolved)) => {
+                        info!(
+                            candidate = %candidate,
+                            skipped = rejected.len(),
+                            "Live candidate found"
+                        );
+                        return Ok(LiveCandidate {
+                            domain: candidate.clone(),
+                            resolved,
+                            rejected,
+                        });
+                    }
+                    Some(Err(e)) => rejected.push((candidate.clone(), e.to_string())),
+                    None => break,
+                }
+            }
+
+            let reasons: Vec<String> = rejected
+                .iter()
+                .map(|(candidate, e)| format!("{}: {}", candidate, e))
+                .collect();
+            anyhow::bail!(
+                "No live candidate among {}: {}",
+                candidates.len(),
+                reasons.join("; ")
+            )
+        };
+
+        match self.deadline {
+            Some(limit) => tokio::time::timeout(limit, search)
                 .await
-                .unwrap_or_else(|e| Err(anyhow::anyhow!("Task failed: {}", e)));
-            results.push(result);
+                .unwrap_or_else(|_| Err(DnsError::Deadline(limit).into())),
+            None => search.await,
         }
-
-        results
     }
 
-    /// Race mode for raw data (ECH parsing)
-    pub async fn resolve_batch_race_raw(
+    /// Resolve one candidate and run it through the validators in order
+    async fn check_candidate(
         &self,
-        hostnames: &[String],
-        protocol: &Protocol,
+        context: &CandidateContext<'_>,
+        chain: &ProtocolChain,
+        candidate: &str,
+        validators: &[Box<dyn Validator>],
         type_code: u16,
-        verbose: bool,
-    ) -> Vec<Result<(Vec<u8>, Provider, Duration)>> {
-        let mut handles: Vec<JoinHandle<Result<(Vec<u8>, Provider, Duration)>>> = Vec::new();
-
-        for hostname in hostnames {
-            let hostname = hostname.clone();
-            let doh = Arc::clone(&self.doh);
-            let dot = Arc::clone(&self.dot);
-            let doh3 = Arc::clone(&self.doh3);
-            let protocol = protocol.clone();
-
-            let handle = tokio::spawn(async move {
-                Self::race_providers_raw(hostname, doh, dot, doh3, protocol, type_code, verbose)
-                    .await
-            });
+    ) -> Result<Resolved<Vec<String>>> {
+        let resolved = self
+            .resolve_with(
+                candidate,
+                context.strategy,
+                chain,
+                context.protocols,
+                type_code,
+            )
+            .await;
 
-            handles.push(handle);
-        }
+        let result = match resolved {
+            Ok(resolved) => {
+                let mut verdict = Ok(());
+                for validator in validators {
+                    verdict = validator.validate(context, candidate, &resolved).await;
+                    if verdict.is_err() {
+                        break;
+                    }
+                }
+                verdict.map(|_| resolved)
+            }
+            Err(e) => Err(e),
+        };
 
-        let mut results = Vec::new();
-        for handle in handles {
-            let result = handle
-                .await
-                .unwrap_or_else(|e| Err(anyhow::anyhow!("Task failed: {}", e)));
-            results.push(result);
+        if let Err(e) = &result {
+            info!(candidate, error = %e, "Candidate rejected");
         }
-
-        results
+        result
     }
 
-    /// Race all providers for a single hostname - first successful response wins
-    async fn race_providers(
-        hostname: String,
-        doh: Arc<DohResolver>,
-        dot: Arc<DotResolver>,
-        doh3: Arc<Doh3Resolver>,
-        protocol: Protocol,
+    /// Resolve one hostname the way `strategy` prescribes
+    async fn resolve_with<T: Answer>(
+        &self,
+        hostname: &str,
+        strategy: &Strategy,
+        chain: &ProtocolChain,
+        protocols: &[Protocol],
         type_code: u16,
-        verbose: bool,
-    ) -> Result<(Vec<String>, Provider, Duration)> {
-        let providers = Provider::all();
-
-        if verbose {
-            eprintln!(
-                "  [verbose] Racing {} providers for {} (type {})",
-                providers.len(),
-                hostname,
-                crate::RecordType::from_code(type_code)
-            );
+    ) -> Result<Resolved<T>> {
+        let router = self.router();
+        let rule = router.as_deref().and_then(|router| router.route(hostname));
+        if let Some(rule) = rule {
+            info!(hostname, rule = %rule, "Matched routing rule");
         }
 
-        type RaceFuture = Pin<
-            Box<
-                dyn std::future::Future<
-                        Output = Result<(Vec<String>, Provider, Duration), anyhow::Error>,
-                    > + Send,
-            >,
-        >;
+        let routed_chain;
+        let (strategy, chain, protocols) = match rule.map(|rule| &rule.route) {
+            None => (strategy, chain, protocols),
+            Some(Route::Refuse) => return Err(DnsError::Refused.into()),
+            Some(Route::Forward {
+                strategy,
+                protocols: None,
+            }) => (strategy, chain, protocols),
+            Some(Route::Forward {
+                strategy,
+                protocols: Some(routed),
+            }) => {
+                routed_chain = ProtocolChain::new(routed);
+                (strategy, &routed_chain, routed.as_slice())
+            }
+        };
+
+        let result = match strategy {
+            Strategy::Provider(provider) => {
+                self.resolve_chain(hostname, provider, chain, type_code)
+                    .await
+            }
+            Strategy::Race => self.race_providers(hostname, chain, type_code).await,
+            Strategy::ProtocolRace(provider) => {
+                self.race_protocols(hostname, provider, protocols, type_code)
+                    .await
+            }
+            Strategy::Fastest => {
+                let primary = protocols.first().copied().unwrap_or(Protocol::Doh);
+                let provider = self.stats.select(primary);
+                self.resolve_chain(hostname, &provider, chain, type_code)
+                    .await
+            }
+            Strategy::Hedged(provider, policy) => {
+                self.hedge_providers(hostname, provider, chain, type_code, policy)
+                    .await
+            }
+        };
+        self.or_stale(hostname, type_code, result)
+    }
 
-        let futures: Vec<RaceFuture> = providers
+    /// One lookup future per provider for `hostname`, each logging its own outcome
+    fn provider_futures<'a, T: Answer>(
+        &'a self,
+        hostname: &'a str,
+        chain: &'a ProtocolChain,
+        type_code: u16,
+    ) -> Vec<(Provider, BoxFuture<'a, Result<Resolved<T>>>)> {
+        Provider::all()
             .into_iter()
             .map(|provider| {
-                let hostname = hostname.clone();
-                let config = DnsProviderConfig::from_provider(&provider);
-                let doh = Arc::clone(&doh);
-                let dot = Arc::clone(&dot);
-                let doh3 = Arc::clone(&doh3);
-                let protocol = protocol.clone();
-                let verbose = verbose;
-
-                Box::pin(async move {
-                    let start = Instant::now();
-
-                    let result = match protocol {
-                        Protocol::Doh => doh.resolve(&hostname, &config, type_code, verbose).await,
-                        Protocol::Dot => dot.resolve(&hostname, &config, type_code, verbose).await,
-                        Protocol::Doh3 => {
-                            doh3.resolve(&hostname, &config, type_code, verbose).await
-                        }
-                    };
-
-                    let elapsed = start.elapsed();
-
-                    match result {
-                        Ok(addresses) => {
-                            if verbose {
-                                eprintln!(
-                                    "  [verbose] ✓ {:?} responded for {} in {:.2?} with {} records",
-                                    provider,
-                                    hostname,
-                                    elapsed,
-                                    addresses.len()
-                                );
-                            }
-                            Ok((addresses, provider, elapsed))
-                        }
-                        Err(e) => {
-                            if verbose {
-                                eprintln!(
-                                    "  [verbose] ✗ {:?} failed for {} in {:.2?}: {}",
-                                    provider, hostname, elapsed, e
-                                );
-                            }
-                            Err(e)
+                let future = {
+                    let provider = provider.clone();
+                    async move {
+                        let result = self
+                            .resolve_chain::<T>(hostname, &provider, chain, type_code)
+                            .await;
+
+                        match &result {
+                            Ok(resolved) => info!(
+                                hostname,
+                                ?provider,
+                                elapsed = ?resolved.elapsed,
+                                "Responded with {}",
+                                resolved.data.summary()
+                            ),
+                            Err(e) => info!(hostname, ?provider, error = %e, "Failed"),
                         }
+                        result
                     }
-                }) as RaceFuture
+                    .boxed()
+                };
+                (provider, future)
             })
+            .collect()
+    }
+
+    /// Race all providers for a single hostname - first successful response wins
+    async fn race_providers<T: Answer>(
+        &self,
+        hostname: &str,
+        chain: &ProtocolChain,
+        type_code: u16,
+    ) -> Result<Resolved<T>> {
+        let futures: Vec<_> = self
+            .provider_futures::<T>(hostname, chain, type_code)
+            .into_iter()
+            .map(|(_, future)| future)
             .collect();
 
+        info!(
+            hostname,
+            record_type = %crate::RecordType::from_code(type_code),
+            "Racing {} providers",
+            futures.len()
+        );
+
         if futures.is_empty() {
             return Err(anyhow::anyhow!("No providers available"));
         }
@@ -284,118 +987,254 @@ impl DnsResolver {
         // Race all providers - first success wins
         match select_ok(futures).await {
             Ok((result, _remaining)) => {
-                if verbose {
-                    eprintln!(
-                        "  [verbose] Race winner for {}: {:?} in {:.2?}",
-                        hostname, result.1, result.2
-                    );
+                info!(
+                    hostname,
+                    provider = ?result.provider,
+                    protocol = ?result.protocol,
+                    elapsed = ?result.elapsed,
+                    "Race won"
+                );
+                // Cache hits finish in whatever order they are polled, so they prove nothing
+                if !result.cached && !result.local {
+                    self.metrics.race_win(&result.provider, result.protocol);
                 }
                 Ok(result)
             }
-            Err(e) => Err(anyhow::anyhow!("All providers failed: {}", e)),
+            Err(e) => {
+                let message = format!("All providers failed: {}", e);
+                Err(e.context(message))
+            }
         }
     }
 
-    /// Race all providers for raw data (ECH)
-    async fn race_providers_raw(
-        hostname: String,
-        doh: Arc<DohResolver>,
-        dot: Arc<DotResolver>,
-        doh3: Arc<Doh3Resolver>,
-        protocol: Protocol,
+    /// Consensus mode: query every provider for each hostname and compare the answers
+    pub async fn resolve_batch_consensus(
+        &self,
+        hostnames: &[String],
+        protocols: &[Protocol],
+        record_type: &RecordType,
+        wait_for: Option<usize>,
+    ) -> Vec<Result<Consensus>> {
+        let type_code = record_type.to_type_code();
+        let chain = Arc::new(ProtocolChain::new(protocols));
+
+        self.spawn_batch(hostnames, |resolver, hostname| {
+            let chain = Arc::clone(&chain);
+            async move {
+                resolver
+                    .consensus_providers(&hostname, &chain, type_code, wait_for)
+                    .await
+            }
+        })
+        .await
+    }
+
+    /// Collect answers from all providers (or the first `wait_for` successes) and compare them
+    async fn consensus_providers(
+        &self,
+        hostname: &str,
+        chain: &ProtocolChain,
         type_code: u16,
-        verbose: bool,
-    ) -> Result<(Vec<u8>, Provider, Duration)> {
-        let providers = Provider::all();
-
-        if verbose {
-            eprintln!(
-                "  [verbose] Racing {} providers for {} (type {}, raw)",
-                providers.len(),
-                hostname,
-                crate::RecordType::from_code(type_code)
-            );
+        wait_for: Option<usize>,
+    ) -> Result<Consensus> {
+        // A cache shared between providers would otherwise hand all of them one answer
+        let resolver = self.own_answers();
+        let futures = resolver.provider_futures::<Vec<String>>(hostname, chain, type_code);
+        let wanted = wait_for.unwrap_or(futures.len()).min(futures.len());
+
+        info!(
+            hostname,
+            record_type = %crate::RecordType::from_code(type_code),
+            "Collecting {} of {} providers",
+            wanted,
+            futures.len()
+        );
+
+        let mut pending: FuturesUnordered<_> = futures
+            .into_iter()
+            .map(|(provider, future)| future.map(move |result| (provider, result)))
+            .collect();
+
+        let mut answers = Vec::new();
+        let mut failures = Vec::new();
+        while let Some((provider, result)) = pending.next().await {
+            match result {
+                Ok(resolved) => answers.push(resolved),
+                Err(e) => failures.push((provider, e.to_string())),
+            }
+            if answers.len() >= wanted {
+                break;
+            }
         }
 
-        type RaceFuture = Pin<
-            Box<
-                dyn std::future::Future<
-                        Output = Result<(Vec<u8>, Provider, Duration), anyhow::Error>,
-                    > + Send,
-            >,
-        >;
-
-        let futures: Vec<RaceFuture> =
-            providers
-                .into_iter()
-                .map(|provider| {
-                    let hostname = hostname.clone();
-                    let config = DnsProviderConfig::from_provider(&provider);
-                    let doh = Arc::clone(&doh);
-                    let dot = Arc::clone(&dot);
-                    let doh3 = Arc::clone(&doh3);
-                    let protocol = protocol.clone();
-                    let verbose = verbose;
-
-                    Box::pin(async move {
-                        let start = Instant::now();
-
-                        let result = match protocol {
-                            Protocol::Doh => {
-                                doh.resolve_raw(&hostname, &config, type_code, verbose)
-                                    .await
-                            }
-                            Protocol::Dot => {
-                                dot.resolve_raw(&hostname, &config, type_code, verbose)
-                                    .await
-                            }
-                            Protocol::Doh3 => {
-                                doh3.resolve_raw(&hostname, &config, type_code, verbose)
-                                    .await
-                            }
-                        };
-
-                        let elapsed = start.elapsed();
-
-                        match result {
-                            Ok(data) => {
-                                if verbose {
-                                    eprintln!(
-                                    "  [verbose] ✓ {:?} responded for {} in {:.2?} with {} bytes",
-                                    provider, hostname, elapsed, data.len()
-                                );
-                                }
-                                Ok((data, provider, elapsed))
-                            }
-                            Err(e) => {
-                                if verbose {
-                                    eprintln!(
-                                        "  [verbose] ✗ {:?} failed for {} in {:.2?}: {}",
-                                        provider, hostname, elapsed, e
-                                    );
-                                }
-                                Err(e)
-                            }
-                        }
-                    }) as RaceFuture
-                })
+        if answers.is_empty() {
+            let reasons: Vec<String> = failures
+                .iter()
+                .map(|(provider, e)| format!("{:?}: {}", provider, e))
                 .collect();
+            anyhow::bail!("All providers failed: {}", reasons.join("; "));
+        }
+
+        Ok(Consensus::evaluate(answers, failures))
+    }
+
+    /// Send to `primary`; if it has not answered within the hedge delay (or failed),
+    /// start the secondary provider and keep whichever answers first
+    async fn hedge_providers<T: Answer>(
+        &self,
+        hostname: &str,
+        primary: &Provider,
+        chain: &ProtocolChain,
+        type_code: u16,
+        policy: &HedgePolicy,
+    ) -> Result<Resolved<T>> {
+        let protocol = chain
+            .candidates(primary)
+            .first()
+            .copied()
+            .unwrap_or(Protocol::Doh);
+        // The secondary is only picked once the hedge fires: picking one counts towards
+        // the re-probe interval and may log a probe for a provider never asked
+        let delay = policy.delay_for(primary, protocol, &self.stats);
+        let mut first = self
+            .resolve_chain::<T>(hostname, primary, chain, type_code)
+            .boxed();
+
+        let early = tokio::select! {
+            result = &mut first => Some(result),
+            _ = tokio::time::sleep(delay) => None,
+        };
+
+        match early {
+            Some(Ok(resolved)) => {
+                info!(
+                    hostname,
+                    provider = ?primary,
+                    elapsed = ?resolved.elapsed,
+                    ?delay,
+                    "Answered before the hedge fired"
+                );
+                return Ok(resolved);
+            }
+            Some(Err(e)) => {
+                let secondary = policy.secondary_for(primary, protocol, &self.stats);
+                info!(
+                    hostname,
+                    provider = ?primary,
+                    error = %e,
+                    "Hedge fired early, asking {:?}",
+                    secondary
+                );
+                return self
+                    .resolve_chain::<T>(hostname, &secondary, chain, type_code)
+                    .await
+                    .map_err(|second| {
+                        let message = format!(
+                            "Both providers failed: {:?}: {}; {:?}: {}",
+                            primary, e, secondary, second
+                        );
+                        second.context(message)
+                    });
+            }
+            None => {}
+        }
+
+        let secondary = policy.secondary_for(primary, protocol, &self.stats);
+        info!(
+            hostname,
+            provider = ?primary,
+            ?delay,
+            "No answer in time, hedge fired, asking {:?}",
+            secondary
+        );
+        let second = self
+            .resolve_chain::<T>(hostname, &secondary, chain, type_code)
+            .boxed();
+
+        // Whichever answers first wins; dropping the other future cancels it
+        let winner = select_ok([first, second])
+            .await
+            .map(|(resolved, _loser)| resolved);
+        match winner {
+            Ok(resolved) => {
+                info!(
+                    hostname,
+                    provider = ?resolved.provider,
+                    elapsed = ?resolved.elapsed,
+                    "Hedge race won, cancelling the other request"
+                );
+                Ok(resolved)
+            }
+            Err(e) => {
+                let message = format!("Both providers failed: {}", e);
+                Err(e.context(message))
+            }
+        }
+    }
+
+    /// Race all `protocols` against `provider` for a single hostname - first success wins
+    async fn race_protocols<T: Answer>(
+        &self,
+        hostname: &str,
+        provider: &Provider,
+        protocols: &[Protocol],
+        type_code: u16,
+    ) -> Result<Resolved<T>> {
+        info!(
+            hostname,
+            ?provider,
+            record_type = %crate::RecordType::from_code(type_code),
+            "Racing {} protocols",
+            protocols.len()
+        );
+
+        let futures: Vec<BoxFuture<'_, Result<Resolved<T>>>> = protocols
+            .iter()
+            .map(|protocol| {
+                async move {
+                    let result = self
+                        .resolve_one::<T>(hostname, provider, protocol, type_code)
+                        .await;
+                    match &result {
+                        Ok(resolved) => info!(
+                            hostname,
+                            ?provider,
+                            ?protocol,
+                            elapsed = ?resolved.elapsed,
+                            "Responded with {}",
+                            resolved.data.summary()
+                        ),
+                        Err(e) => info!(hostname, ?provider, ?protocol, error = %e, "Failed"),
+                    }
+                    result
+                }
+                .boxed()
+            })
+            .collect();
 
         if futures.is_empty() {
-            return Err(anyhow::anyhow!("No providers available"));
+            return Err(anyhow::anyhow!("No protocols configured"));
         }
 
         match select_ok(futures).await {
             Ok((result, _remaining)) => {
-                if verbose {
-                    eprintln!(
-                        "  [verbose] Race winner for {}: {:?} in {:.2?}",
-                        hostname, result.1, result.2
-                    );
+                info!(
+                    hostname,
+                    provider = ?result.provider,
+                    protocol = ?result.protocol,
+                    elapsed = ?result.elapsed,
+                    "Race won"
+                );
+                // Cache hits finish in whatever order they are polled, so they prove nothing
+                if !result.cached && !result.local {
+                    self.metrics.race_win(&result.provider, result.protocol);
                 }
                 Ok(result)
             }
-            Err(e) => Err(anyhow::anyhow!("All providers failed: {}", e)),
+            Err(e) => {
+                let message = format!("All protocols failed: {}", e);
+                Err(e.context(message))
+            }
         }
     }
 }
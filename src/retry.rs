@@ -0,0 +1,87 @@
+use crate::error::{classify, ErrorClass};
+use anyhow::Result;
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// How often and how patiently a failed lookup is retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; 1 disables retries
+    pub max_attempts: u32,
+    /// Backoff before the second attempt, doubled for every attempt after that
+    pub base_delay: Duration,
+    /// Upper bound for a single backoff
+    pub max_delay: Duration,
+    /// Error classes that are worth another attempt
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            retry_on: vec![
+                ErrorClass::Timeout,
+                ErrorClass::Connection,
+                ErrorClass::Handshake,
+                ErrorClass::ServerError,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter: a random delay in `[0, base * 2^(attempt-1)]`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << (attempt - 1).min(16))
+            .min(self.max_delay);
+        let millis = exp.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    /// Run `op` until it succeeds, fails with a non-retryable error or runs out of attempts.
+    /// Returns the final result together with the number of attempts made.
+    pub async fn run<T, F, Fut>(&self, label: &str, mut op: F) -> (Result<T>, u32)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 1;
+
+        loop {
+            if max_attempts > 1 {
+                debug!(attempt, max_attempts, "Attempting {}", label);
+            }
+
+            let err = match op().await {
+                Ok(value) => return (Ok(value), attempt),
+                Err(e) => e,
+            };
+
+            let class = classify(&err);
+            if attempt >= max_attempts || !self.retry_on.contains(&class) {
+                return (Err(err), attempt);
+            }
+
+            let delay = self.backoff(attempt);
+            info!(
+                attempt,
+                class = %class,
+                error = %err,
+                "Attempt for {} failed, retrying in {:.2?}",
+                label,
+                delay
+            );
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use crate::providers::DnsProviderConfig;
+use crate::resolver::Strategy;
+use crate::{Protocol, Provider};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// Rules file as written by the user
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    upstream: HashMap<String, UpstreamSpec>,
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+}
+
+/// A resolver that is not one of the built-in providers, e.g. an internal one
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamSpec {
+    /// IP address or host the DoT and DoH3 transports connect to
+    address: String,
+    /// Name on the server certificate; defaults to `address`
+    tls_name: Option<String>,
+    #[serde(default = "default_dot_port")]
+    dot_port: u16,
+    #[serde(default = "default_doh3_port")]
+    doh3_port: u16,
+    /// DoH endpoint; defaults to `https://<tls_name>/dns-query`
+    doh_url: Option<String>,
+}
+
+fn default_dot_port() -> u16 {
+    853
+}
+
+fn default_doh3_port() -> u16 {
+    443
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    suffix: Option<String>,
+    exact: Option<String>,
+    wildcard: Option<String>,
+    regex: Option<String>,
+    #[serde(default)]
+    refuse: bool,
+    upstream: Option<String>,
+    #[serde(default)]
+    protocols: Vec<String>,
+}
+
+/// How a rule matches query names, which are lowercased and stripped of the root dot
+enum Matcher {
+    /// The name itself or any name under it
+    Suffix(String),
+    Exact(String),
+    /// Glob over the whole name; `*` matches any run of characters, dots included
+    Wildcard(String, Regex),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Matcher::Suffix(suffix) => {
+                name == suffix
+                    || name
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|rest| rest.ends_with('.'))
+            }
+            Matcher::Exact(exact) => name == exact,
+            Matcher::Wildcard(_, regex) | Matcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Suffix(suffix) => write!(f, "suffix {}", suffix),
+            Matcher::Exact(exact) => write!(f, "exact {}", exact),
+            Matcher::Wildcard(glob, _) => write!(f, "wildcard {}", glob),
+            Matcher::Regex(regex) => write!(f, "regex {}", regex),
+        }
+    }
+}
+
+/// What happens to queries matching a rule
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// Answer REFUSED without asking any upstream
+    Refuse,
+    /// Resolve with `strategy`, over `protocols` when the rule names any
+    Forward {
+        strategy: Strategy,
+        protocols: Option<Vec<Protocol>>,
+    },
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Refuse => write!(f, "refuse"),
+            Route::Forward {
+                strategy,
+                protocols: None,
+            } => write!(f, "{}", strategy),
+            Route::Forward {
+                strategy,
+                protocols: Some(protocols),
+            } => write!(f, "{} over {:?}", strategy, protocols),
+        }
+    }
+}
+
+pub struct Rule {
+    matcher: Matcher,
+    pub route: Route,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} → {}", self.matcher, self.route)
+    }
+}
+
+/// Ordered split-DNS rules and the custom upstreams they forward to; the first
+/// matching rule decides, names matching none use the default strategy
+pub struct Router {
+    rules: Vec<Rule>,
+    upstreams: HashMap<String, DnsProviderConfig>,
+}
+
+impl Router {
+    /// Load rules from a TOML file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read rules file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid rules file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: RulesFile = toml::from_str(text)?;
+
+        let upstreams: HashMap<String, DnsProviderConfig> = file
+            .upstream
+            .into_iter()
+            .map(|(name, spec)| {
+                let config = spec.into_config(&name);
+                (name, config)
+            })
+            .collect();
+
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                spec.into_rule(&upstreams)
+                    .with_context(|| format!("Rule {}", i + 1))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { rules, upstreams })
+    }
+
+    /// The first rule matching `hostname`, if any
+    pub fn route(&self, hostname: &str) -> Option<&Rule> {
+        let name = hostname.trim_end_matches('.').to_ascii_lowercase();
+        self.rules.iter().find(|rule| rule.matcher.matches(&name))
+    }
+
+    /// Endpoints of the custom upstream called `name`
+    pub fn upstream(&self, name: &str) -> Option<&DnsProviderConfig> {
+        self.upstreams.get(name)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
+
+impl UpstreamSpec {
+    fn into_config(self, name: &str) -> DnsProviderConfig {
+        let tls_name = self.tls_name.unwrap_or_else(|| self.address.clone());
+        let doh_url = self
+            .doh_url
+            .unwrap_or_else(|| format!("https://{}/dns-query", tls_name));
+
+        DnsProviderConfig {
+            name: name.to_string(),
+            doh_url: doh_url.clone(),
+            dot_host: self.address.clone(),
+            dot_port: self.dot_port,
+            dot_hostname: tls_name.clone(),
+            doh3_url: doh_url,
+            doh3_host: self.address,
+            doh3_port: self.doh3_port,
+            doh3_hostname: tls_name,
+        }
+    }
+}
+
+impl RuleSpec {
+    fn into_rule(self, upstreams: &HashMap<String, DnsProviderConfig>) -> Result<Rule> {
+        let lower = |pattern: String| pattern.trim_end_matches('.').to_ascii_lowercase();
+        let matcher = match (self.suffix, self.exact, self.wildcard, self.regex) {
+            (Some(suffix), None, None, None) => Matcher::Suffix(lower(suffix)),
+            (None, Some(exact), None, None) => Matcher::Exact(lower(exact)),
+            (None, None, Some(glob), None) => {
+                let glob = lower(glob);
+                let pattern = glob
+                    .split('*')
+                    .map(regex::escape)
+                    .collect::<Vec<_>>()
+                    .join(".*");
+                Matcher::Wildcard(glob, Regex::new(&format!("^{}$", pattern))?)
+            }
+            (None, None, None, Some(regex)) => {
+                Matcher::Regex(Regex::new(&regex).context("Invalid regex")?)
+            }
+            _ => anyhow::bail!("Needs exactly one of suffix, exact, wildcard or regex"),
+        };
+
+        let route = match (self.refuse, self.upstream) {
+            (true, None) if self.protocols.is_empty() => Route::Refuse,
+            (true, None) => anyhow::bail!("A refusing rule takes no protocols"),
+            (false, Some(upstream)) => {
+                let strategy = match upstream.as_str() {
+                    "race" => Strategy::Race,
+                    "fastest" => Strategy::Fastest,
+                    name if upstreams.contains_key(name) => {
+                        Strategy::Provider(Provider::Custom(name.to_string()))
+                    }
+                    name => Strategy::Provider(
+                        Provider::from_str(name, true)
+                            .map_err(|_| anyhow::anyhow!("Unknown upstream '{}'", name))?,
+                    ),
+                };
+                let protocols = self
+                    .protocols
+                    .iter()
+                    .map(|p| {
+                        Protocol::from_str(p, true)
+                            .map_err(|_| anyhow::anyhow!("Unknown protocol '{}'", p))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Route::Forward {
+                    strategy,
+                    protocols: (!protocols.is_empty()).then_some(protocols),
+                }
+            }
+            _ => anyhow::bail!("Needs either refuse = true or an upstream"),
+        };
+
+        Ok(Rule { matcher, route })
+    }
+}
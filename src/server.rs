@@ -0,0 +1,366 @@
+use crate::blocklist::{BlockPolicy, Blocklist};
+use crate::error::is_refused;
+use crate::message;
+use crate::querylog::{Entry, QueryLog, Status};
+use crate::resolver::{DnsResolver, Strategy};
+use crate::{Protocol, Provider, RecordType};
+use anyhow::{Context, Result};
+use colored::*;
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{info, info_span, Instrument};
+use trust_dns_proto::op::{Message, MessageType, OpCode, ResponseCode};
+use trust_dns_proto::rr::DNSClass;
+
+/// TTL of the 0.0.0.0 / :: answers given for blocked names
+const BLOCKED_TTL: u32 = 60;
+
+/// Idle TCP connections are closed after this long without a query (RFC 7766)
+const TCP_IDLE: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept, as hyper's `AddrIncoming` does, so running out of
+/// file descriptors does not turn the accept loop into a busy loop
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+/// The next connection on `listener`; failed accepts are logged as `what` and
+/// retried after a pause rather than ending the server
+pub async fn accept(listener: &TcpListener, what: &str) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                info!(error = %e, "{} accept failed", what);
+                tokio::time::sleep(ACCEPT_RETRY).await;
+            }
+        }
+    }
+}
+
+/// How a query reached the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Https,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "UDP"),
+            Transport::Tcp => write!(f, "TCP"),
+            Transport::Https => write!(f, "HTTPS"),
+        }
+    }
+}
+
+/// Where forwarded queries go; can be switched while the server runs
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub strategy: Strategy,
+    pub protocols: Vec<Protocol>,
+}
+
+/// Forwards client queries upstream through the resolver and relays the
+/// provider's response; shared by every listener
+pub struct Forwarder {
+    resolver: DnsResolver,
+    upstream: RwLock<Upstream>,
+    query_log: Option<QueryLog>,
+}
+
+/// Plain DNS (Do53) listener for local applications
+pub struct StubServer {
+    forwarder: Arc<Forwarder>,
+}
+
+impl StubServer {
+    pub fn new(forwarder: Arc<Forwarder>) -> Self {
+        Self { forwarder }
+    }
+
+    /// Answer queries on `listen` over both UDP and TCP until an error stops a listener
+    pub async fn run(self, listen: SocketAddr) -> Result<()> {
+        let udp = UdpSocket::bind(listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} (UDP)", listen))?;
+        let tcp = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} (TCP)", listen))?;
+
+        let server = Arc::new(self);
+        tokio::try_join!(server.clone().serve_udp(udp), server.serve_tcp(tcp))?;
+        Ok(())
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; u16::MAX as usize];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // ICMP errors for earlier replies surface here; they only concern that client
+                Err(e) => {
+                    self.forwarder.log_error("UDP receive failed", &e.into());
+                    continue;
+                }
+            };
+
+            let query = buf[..len].to_vec();
+            let server = Arc::clone(&self);
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                if let Some(response) = server.forwarder.answer(&query, peer, Transport::Udp).await
+                {
+                    if let Err(e) = socket.send_to(&response, peer).await {
+                        server.forwarder.log_error("UDP send failed", &e.into());
+                    }
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = accept(&listener, "TCP").await;
+
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream, peer).await {
+                    server
+                        .forwarder
+                        .log_error(&format!("TCP connection from {} failed", peer), &e);
+                }
+            });
+        }
+    }
+
+    /// Answer length-prefixed queries on one TCP connection until the client closes it
+    /// or goes idle
+    async fn serve_connection(&self, mut stream: TcpStream, peer: SocketAddr) -> Result<()> {
+        loop {
+            let mut len_buf = [0u8; 2];
+            match tokio::time::timeout(TCP_IDLE, stream.read_exact(&mut len_buf)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => return Ok(()),
+            }
+
+            let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+            stream.read_exact(&mut query).await?;
+
+            if let Some(response) = self.forwarder.answer(&query, peer, Transport::Tcp).await {
+                stream
+                    .write_all(&(response.len() as u16).to_be_bytes())
+                    .await?;
+                stream.write_all(&response).await?;
+                stream.flush().await?;
+            }
+        }
+    }
+}
+
+impl Forwarder {
+    pub fn new(resolver: DnsResolver, protocols: Vec<Protocol>, strategy: Strategy) -> Self {
+        Self {
+            resolver,
+            upstream: RwLock::new(Upstream {
+                strategy,
+                protocols,
+            }),
+            query_log: None,
+        }
+    }
+
+    /// Answer names on the block lists locally instead of forwarding them
+    pub fn with_blocklist(self, blocklist: Option<Blocklist>) -> Self {
+        self.resolver
+            .update_config(|config| config.blocklist = blocklist.map(Arc::new));
+        self
+    }
+
+    /// Record every answered query in `log`
+    pub fn with_query_log(mut self, log: Option<QueryLog>) -> Self {
+        self.query_log = log;
+        self
+    }
+
+    pub fn resolver(&self) -> &DnsResolver {
+        &self.resolver
+    }
+
+    pub fn blocklist(&self) -> Option<Arc<Blocklist>> {
+        self.resolver.config().blocklist.clone()
+    }
+
+    /// Re-read every block and allow list, replacing the lists in use only if all load
+    pub fn reload_blocklist(&self) -> Result<Option<Arc<Blocklist>>> {
+        let Some(current) = self.blocklist() else {
+            return Ok(None);
+        };
+        Ok(Some(self.set_blocklist(current.reload()?)))
+    }
+
+    /// Replace the lists in use for every query that starts from now on
+    pub fn set_blocklist(&self, blocklist: Blocklist) -> Arc<Blocklist> {
+        let blocklist = Arc::new(blocklist);
+        self.resolver
+            .update_config(|config| config.blocklist = Some(Arc::clone(&blocklist)));
+        blocklist
+    }
+
+    pub fn upstream(&self) -> Upstream {
+        self.upstream.read().unwrap().clone()
+    }
+
+    /// Send queries from now on to `upstream`; queries already in flight finish as they were
+    pub fn set_upstream(&self, upstream: Upstream) {
+        *self.upstream.write().unwrap() = upstream;
+    }
+
+    /// The response to send back for one client query, or `None` to stay silent
+    pub async fn answer(
+        &self,
+        query: &[u8],
+        peer: SocketAddr,
+        transport: Transport,
+    ) -> Option<Vec<u8>> {
+        let request = match Message::from_vec(query) {
+            Ok(request) => request,
+            Err(e) => {
+                self.log_error(&format!("Malformed query from {}", peer), &e.into());
+                return None;
+            }
+        };
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+
+        let reply = |code| message::reply(&request, code).ok();
+        if request.op_code() != OpCode::Query {
+            return reply(ResponseCode::NotImp);
+        }
+        let [question] = request.queries() else {
+            return reply(ResponseCode::FormErr);
+        };
+        if question.query_class() != DNSClass::IN {
+            return reply(ResponseCode::NotImp);
+        }
+
+        let hostname = question.name().to_ascii();
+        let type_code = u16::from(question.query_type());
+
+        let span = info_span!(
+            "serve",
+            %peer,
+            %transport,
+            id = request.id(),
+            hostname = %hostname.trim_end_matches('.'),
+            record_type = %RecordType::from_code(type_code)
+        );
+        span.in_scope(|| info!("Query received"));
+
+        let start = Instant::now();
+        let (response, status, upstream) = self
+            .forward(&request, &hostname, type_code, peer)
+            .instrument(span)
+            .await;
+        let mut response = response?;
+
+        if transport == Transport::Udp && response.len() > request.max_payload() as usize {
+            response = message::truncated(&request).ok()?;
+        }
+        message::set_id(&mut response, request.id());
+
+        self.resolver.metrics().served(transport, status);
+        if let Some(log) = &self.query_log {
+            let entry = Entry {
+                transport,
+                name: hostname.trim_end_matches('.'),
+                record_type: question.query_type().to_string(),
+                provider: upstream.as_ref().map(|(p, _)| format!("{:?}", p)),
+                protocol: upstream.as_ref().map(|(_, p)| format!("{:?}", p)),
+                rcode: message::response_code(&response)
+                    .map(|code| format!("{:?}", code).to_uppercase())
+                    .unwrap_or_default(),
+                latency_ms: start.elapsed().as_micros() as f64 / 1000.0,
+                status,
+            };
+            if let Err(e) = log.write(peer.ip(), &entry) {
+                eprintln!("{} {:#}", "Warning:".yellow().bold(), e);
+            }
+        }
+        Some(response)
+    }
+
+    /// Answer one validated question from the block lists or through the resolver,
+    /// with how it was answered and, if asked upstream, by whom
+    async fn forward(
+        &self,
+        request: &Message,
+        hostname: &str,
+        type_code: u16,
+        peer: SocketAddr,
+    ) -> (Option<Vec<u8>>, Status, Option<(Provider, Protocol)>) {
+        let reply = |code| message::reply(request, code).ok();
+
+        // One configuration for the whole query, even if a reload lands halfway
+        let resolver = self.resolver.pinned();
+        if let Some(blocklist) = &resolver.config().blocklist {
+            if let Some(list) = blocklist.check(hostname) {
+                info!(
+                    list = %list.path.display(),
+                    policy = ?blocklist.policy(),
+                    "Blocked"
+                );
+                let response = match blocklist.policy() {
+                    BlockPolicy::Nxdomain => reply(ResponseCode::NXDomain),
+                    BlockPolicy::Null => message::null_answer(request, BLOCKED_TTL).ok(),
+                    BlockPolicy::Refused => reply(ResponseCode::Refused),
+                };
+                return (response, Status::Blocked, None);
+            }
+        }
+
+        let Upstream {
+            strategy,
+            protocols,
+        } = self.upstream();
+        let result = resolver
+            .resolve_message(hostname, &protocols, &strategy, type_code)
+            .await;
+
+        match result {
+            Ok(resolved) => {
+                let status = if resolved.local {
+                    Status::Static
+                } else if resolved.stale {
+                    Status::Stale
+                } else if resolved.cached {
+                    Status::Cached
+                } else {
+                    Status::Resolved
+                };
+                let upstream = (!resolved.local).then_some((resolved.provider, resolved.protocol));
+                (Some(resolved.data.0), status, upstream)
+            }
+            Err(e) if is_refused(&e) => (reply(ResponseCode::Refused), Status::Refused, None),
+            Err(e) => {
+                self.log_error(&format!("Failed to resolve {} for {}", hostname, peer), &e);
+                (reply(ResponseCode::ServFail), Status::Failed, None)
+            }
+        }
+    }
+
+    pub fn log_error(&self, what: &str, e: &anyhow::Error) {
+        info!(error = %format!("{:#}", e), "{}", what);
+    }
+}
@@ -0,0 +1,155 @@
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// Work in progress for one key and how many callers are waiting for it
+struct Call<V> {
+    future: Shared<BoxFuture<'static, V>>,
+    waiters: usize,
+}
+
+/// Coalesces identical in-flight work: callers asking for a key that is already
+/// being worked on wait for that work instead of starting their own
+pub struct Group<K, V: Clone> {
+    calls: Mutex<HashMap<K, Call<V>>>,
+}
+
+impl<K, V> Default for Group<K, V>
+where
+    V: Clone,
+{
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// One caller's interest in a call; when the last one goes away unfinished, the
+/// call is forgotten and its work dropped
+struct Waiter<'a, K: Eq + Hash, V: Clone> {
+    group: &'a Group<K, V>,
+    key: K,
+    call: Shared<BoxFuture<'static, V>>,
+}
+
+impl<K: Eq + Hash, V: Clone> Drop for Waiter<'_, K, V> {
+    fn drop(&mut self) {
+        let mut calls = self.group.calls.lock().unwrap();
+        let Some(current) = calls.get_mut(&self.key) else {
+            return;
+        };
+        if current.future.ptr_eq(&self.call) {
+            current.waiters -= 1;
+            if current.waiters == 0 {
+                calls.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<K, V> Group<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Run `work` for `key`, or join the call already running for it.
+    ///
+    /// Returns the result and whether it came from another caller's call. The work
+    /// keeps running as long as any caller is still waiting for it, and is dropped
+    /// (with whatever it holds) once every caller has given up.
+    pub async fn run<F>(&self, key: K, work: F) -> (V, bool)
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let (waiter, joined) = {
+            let mut calls = self.calls.lock().unwrap();
+            let joined = match calls.get_mut(&key) {
+                Some(call) => {
+                    call.waiters += 1;
+                    true
+                }
+                None => {
+                    let call = Call {
+                        future: work.boxed().shared(),
+                        waiters: 1,
+                    };
+                    calls.insert(key.clone(), call);
+                    false
+                }
+            };
+            let waiter = Waiter {
+                group: self,
+                call: calls[&key].future.clone(),
+                key,
+            };
+            (waiter, joined)
+        };
+
+        let value = waiter.call.clone().await;
+
+        // Whoever finishes first forgets the call, so later callers start afresh
+        let mut calls = self.calls.lock().unwrap();
+        if calls
+            .get(&waiter.key)
+            .is_some_and(|current| current.future.ptr_eq(&waiter.call))
+        {
+            calls.remove(&waiter.key);
+        }
+        drop(calls);
+
+        (value, joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn abandoned_call_is_forgotten_and_releases_what_it_holds() {
+        let group = Group::<&str, ()>::default();
+        let semaphore = Arc::new(Semaphore::new(1));
+        let work = {
+            let semaphore = Arc::clone(&semaphore);
+            async move {
+                let _permit = semaphore.acquire_owned().await.unwrap();
+                std::future::pending::<()>().await
+            }
+        };
+
+        let mut first = Box::pin(group.run("key", work));
+        let mut second = Box::pin(group.run("key", async {}));
+        assert!(futures::poll!(first.as_mut()).is_pending());
+        assert!(futures::poll!(second.as_mut()).is_pending());
+        assert_eq!(semaphore.available_permits(), 0);
+
+        drop(first);
+        assert!(group.calls.lock().unwrap().contains_key("key"));
+        assert_eq!(semaphore.available_permits(), 0);
+
+        drop(second);
+        assert!(group.calls.lock().unwrap().is_empty());
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn finished_call_is_shared_then_forgotten() {
+        let group = Group::<&str, u32>::default();
+        let (first, second) = tokio::join!(
+            group.run("key", async {
+                tokio::task::yield_now().await;
+                1
+            }),
+            group.run("key", async { 2 })
+        );
+        assert_eq!(first, (1, false));
+        assert_eq!(second, (1, true));
+        assert!(group.calls.lock().unwrap().is_empty());
+    }
+}
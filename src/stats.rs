@@ -0,0 +1,244 @@
+use crate::{Protocol, Provider};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Weight of the newest sample in the moving averages
+const EWMA_ALPHA: f64 = 0.3;
+
+/// Recent response times kept per provider and protocol for latency percentiles
+const WINDOW_SIZE: usize = 128;
+
+/// Samples needed before a percentile is trusted
+const MIN_SAMPLES: usize = 10;
+
+/// Failure rates are floored at this success rate when scoring, so a provider
+/// that never succeeded still gets a finite (bad) score
+const MIN_SUCCESS_RATE: f64 = 0.05;
+
+/// Moving averages describing how one provider/protocol pair has behaved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderStats {
+    /// `None` until the first successful lookup
+    pub ewma_latency_ms: Option<f64>,
+    pub success_rate: f64,
+    pub samples: u64,
+    /// Unix timestamp (seconds) of the last lookup
+    pub last_used: u64,
+}
+
+impl ProviderStats {
+    /// Lower is better: latency inflated by the failure rate
+    pub fn score(&self) -> f64 {
+        match self.ewma_latency_ms {
+            Some(latency) => latency / self.success_rate.max(MIN_SUCCESS_RATE),
+            None => f64::INFINITY,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StatsEntry {
+    provider: Provider,
+    protocol: Protocol,
+    #[serde(flatten)]
+    stats: ProviderStats,
+}
+
+/// Latency and success history per provider and protocol, shared by all lookups.
+///
+/// Fed with the time of each upstream exchange on its own, so neither retry
+/// backoff nor waiting for a concurrency permit counts against a provider.
+pub struct StatsTracker {
+    stats: Mutex<HashMap<(Provider, Protocol), ProviderStats>>,
+    /// Sliding window of recent successful exchange times, for percentiles
+    recent: Mutex<HashMap<(Provider, Protocol), VecDeque<Duration>>>,
+    selections: AtomicU64,
+    /// Every n-th selection goes to the least recently used provider instead of the best
+    probe_every: u64,
+}
+
+impl StatsTracker {
+    pub fn new(probe_every: u64) -> Self {
+        Self {
+            stats: Mutex::new(HashMap::new()),
+            recent: Mutex::new(HashMap::new()),
+            selections: AtomicU64::new(0),
+            probe_every,
+        }
+    }
+
+    /// Record the outcome of one exchange; `latency` is `None` for a failure
+    pub fn record(&self, provider: &Provider, protocol: Protocol, latency: Option<Duration>) {
+        if let Some(latency) = latency {
+            let mut recent = self.recent.lock().unwrap();
+            let window = recent.entry((provider.clone(), protocol)).or_default();
+            if window.len() == WINDOW_SIZE {
+                window.pop_front();
+            }
+            window.push_back(latency);
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        let success = if latency.is_some() { 1.0 } else { 0.0 };
+
+        let entry = stats
+            .entry((provider.clone(), protocol))
+            .or_insert_with(|| ProviderStats {
+                ewma_latency_ms: None,
+                success_rate: success,
+                samples: 0,
+                last_used: 0,
+            });
+
+        if entry.samples > 0 {
+            entry.success_rate = EWMA_ALPHA * success + (1.0 - EWMA_ALPHA) * entry.success_rate;
+        }
+        if let Some(latency) = latency {
+            let ms = latency.as_secs_f64() * 1000.0;
+            entry.ewma_latency_ms = Some(match entry.ewma_latency_ms {
+                Some(avg) => EWMA_ALPHA * ms + (1.0 - EWMA_ALPHA) * avg,
+                None => ms,
+            });
+        }
+        entry.samples += 1;
+        entry.last_used = unix_now();
+    }
+
+    /// The `p`th percentile exchange time, or `None` if too few samples exist yet
+    pub fn percentile(&self, provider: &Provider, protocol: Protocol, p: f64) -> Option<Duration> {
+        let recent = self.recent.lock().unwrap();
+        let window = recent.get(&(provider.clone(), protocol))?;
+        if window.len() < MIN_SAMPLES {
+            return None;
+        }
+
+        let mut sorted: Vec<Duration> = window.iter().copied().collect();
+        sorted.sort();
+        let rank = ((p.clamp(0.0, 100.0) / 100.0) * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.saturating_sub(1).min(sorted.len() - 1)])
+    }
+
+    /// Pick the provider with the best score for `protocol`.
+    ///
+    /// Providers without history are probed first, and every `probe_every`-th
+    /// selection re-probes the provider that was used least recently so the
+    /// ranking keeps up with changing network conditions.
+    pub fn select(&self, protocol: Protocol) -> Provider {
+        self.select_from(protocol, Provider::all())
+    }
+
+    /// Like `select`, but never picks `excluded`
+    pub fn select_other(&self, protocol: Protocol, excluded: &Provider) -> Provider {
+        let providers = Provider::all()
+            .into_iter()
+            .filter(|p| p != excluded)
+            .collect();
+        self.select_from(protocol, providers)
+    }
+
+    fn select_from(&self, protocol: Protocol, providers: Vec<Provider>) -> Provider {
+        let n = self.selections.fetch_add(1, Ordering::Relaxed) + 1;
+        let stats = self.stats.lock().unwrap();
+        let known = |p: &Provider| stats.get(&(p.clone(), protocol));
+
+        // Spread cold-start probes across all unknown providers
+        let unknown: Vec<Provider> = providers
+            .iter()
+            .filter(|p| known(p).is_none())
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            let unknown = unknown[(n as usize) % unknown.len()].clone();
+            info!(provider = ?unknown, ?protocol, "No history, probing it");
+            return unknown;
+        }
+
+        if self.probe_every > 0 && n % self.probe_every == 0 {
+            if let Some(stale) = providers
+                .iter()
+                .cloned()
+                .min_by_key(|p| known(p).map(|s| s.last_used).unwrap_or(0))
+            {
+                info!(provider = ?stale, ?protocol, "Periodic re-probe");
+                return stale;
+            }
+        }
+
+        let best = providers
+            .into_iter()
+            .min_by(|a, b| {
+                let score = |p: &Provider| known(p).map(ProviderStats::score).unwrap_or(f64::MAX);
+                score(a).total_cmp(&score(b))
+            })
+            .expect("at least one provider");
+
+        if let Some(s) = known(&best) {
+            info!(
+                provider = ?best,
+                ?protocol,
+                latency_ms = s.ewma_latency_ms.unwrap_or(f64::NAN),
+                success_rate = s.success_rate,
+                "Fastest known provider"
+            );
+        }
+
+        best
+    }
+
+    /// Snapshot of all tracked stats, best first
+    pub fn snapshot(&self) -> Vec<(Provider, Protocol, ProviderStats)> {
+        let stats = self.stats.lock().unwrap();
+        let mut entries: Vec<_> = stats
+            .iter()
+            .map(|((provider, protocol), s)| (provider.clone(), *protocol, s.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.2.score().total_cmp(&b.2.score()));
+        entries
+    }
+
+    /// Load history saved by a previous run; a missing file is not an error
+    pub fn load(&self, path: &Path) -> Result<()> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("Failed to read state file"),
+        };
+
+        let entries: Vec<StatsEntry> =
+            serde_json::from_str(&data).context("Failed to parse state file")?;
+
+        let mut stats = self.stats.lock().unwrap();
+        for entry in entries {
+            stats.insert((entry.provider, entry.protocol), entry.stats);
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let entries: Vec<StatsEntry> = self
+            .snapshot()
+            .into_iter()
+            .map(|(provider, protocol, stats)| StatsEntry {
+                provider,
+                protocol,
+                stats,
+            })
+            .collect();
+
+        let data = serde_json::to_string_pretty(&entries)?;
+        std::fs::write(path, data).context("Failed to write state file")
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
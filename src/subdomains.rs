@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+/// A label prepended to every base domain, e.g. `api` → `api.example.com`
+///
+/// Written as `api` (required) or `cdn?` (optional); `@` stands for the base
+/// domain itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subdomain {
+    pub label: String,
+    pub required: bool,
+}
+
+impl Subdomain {
+    /// Full hostname of this subdomain under `base`
+    pub fn under(&self, base: &str) -> String {
+        if self.label == "@" {
+            base.to_string()
+        } else {
+            format!("{}.{}", self.label, base)
+        }
+    }
+}
+
+impl FromStr for Subdomain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, required) = match s.trim().strip_suffix('?') {
+            Some(label) => (label, false),
+            None => (s.trim(), true),
+        };
+        let label = label.trim_matches('.');
+        if label.is_empty() {
+            return Err(format!("empty subdomain label in '{}'", s));
+        }
+        Ok(Self {
+            label: label.to_string(),
+            required,
+        })
+    }
+}
+
+/// Resolution results for every subdomain of one base domain
+pub struct BaseHealth<T> {
+    pub base: String,
+    /// Each subdomain with its full hostname and lookup result
+    pub entries: Vec<(Subdomain, String, anyhow::Result<T>)>,
+}
+
+impl<T> BaseHealth<T> {
+    /// A base domain is healthy only if all of its required subdomains resolved
+    pub fn healthy(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|(subdomain, _, result)| !subdomain.required || result.is_ok())
+    }
+}
+
+/// Every subdomain template applied to every base domain
+pub struct Expansion {
+    bases: Vec<String>,
+    subdomains: Vec<Subdomain>,
+}
+
+impl Expansion {
+    pub fn new(bases: &[String], subdomains: &[Subdomain]) -> Self {
+        let mut unique: Vec<String> = Vec::new();
+        for base in bases {
+            let base = base.trim_end_matches('.').to_string();
+            if !unique.contains(&base) {
+                unique.push(base);
+            }
+        }
+        Self {
+            bases: unique,
+            subdomains: subdomains.to_vec(),
+        }
+    }
+
+    /// Expanded hostnames, grouped by base domain, to be resolved as one batch
+    pub fn hostnames(&self) -> Vec<String> {
+        self.bases
+            .iter()
+            .flat_map(|base| self.subdomains.iter().map(move |s| s.under(base)))
+            .collect()
+    }
+
+    /// Split batch results (in the order of [`Expansion::hostnames`]) back up by base domain
+    pub fn group<T>(&self, results: Vec<anyhow::Result<T>>) -> Vec<BaseHealth<T>> {
+        let mut results = results.into_iter();
+        self.bases
+            .iter()
+            .map(|base| BaseHealth {
+                base: base.clone(),
+                entries: self
+                    .subdomains
+                    .iter()
+                    .zip(results.by_ref())
+                    .map(|(subdomain, result)| (subdomain.clone(), subdomain.under(base), result))
+                    .collect(),
+            })
+            .collect()
+    }
+}
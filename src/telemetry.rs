@@ -0,0 +1,126 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::io::IsTerminal;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// How log events are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One line per event, prefixed with the fields of the spans it happened in
+    Compact,
+    /// Several lines per event, with every field and span on its own line
+    Pretty,
+    /// One JSON object per event, carrying its span and all parent spans
+    Json,
+}
+
+/// Keeps span export running; flushes spans still buffered when dropped
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    otlp: bool,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if self.otlp {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// What `-v` given `verbosity` times shows, unless `RUST_LOG` says otherwise:
+/// warnings only, then per-query decisions, then transport internals, then
+/// everything including the libraries underneath
+fn filter(verbosity: u8) -> EnvFilter {
+    if let Ok(filter) = EnvFilter::try_from_default_env() {
+        return filter;
+    }
+    EnvFilter::new(match verbosity {
+        0 => "warn",
+        1 => "warn,secure_dns_resolver=info",
+        2 => "warn,secure_dns_resolver=debug",
+        _ => "debug,secure_dns_resolver=trace",
+    })
+}
+
+/// Install the global subscriber: events go to stderr in `format`, and with an
+/// `otlp_endpoint` (e.g. `http://localhost:4318`) spans are also exported to an
+/// OpenTelemetry collector over OTLP/HTTP
+pub fn init(verbosity: u8, format: LogFormat, otlp_endpoint: Option<&str>) -> Result<Telemetry> {
+    let ansi = std::io::stderr().is_terminal();
+    let output = match format {
+        LogFormat::Compact => fmt::layer()
+            .compact()
+            .with_ansi(ansi)
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .with_ansi(ansi)
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+
+    // Exported traces need the query spans even when the console shows only warnings
+    #[cfg(feature = "otlp")]
+    let otlp = otlp_endpoint
+        .map(otlp::layer)
+        .transpose()?
+        .map(|layer| layer.with_filter(filter(verbosity.max(1))));
+    #[cfg(not(feature = "otlp"))]
+    let otlp = match otlp_endpoint {
+        Some(_) => anyhow::bail!("Built without OTLP support; rebuild with `--features otlp`"),
+        None => None::<tracing_subscriber::layer::Identity>,
+    };
+
+    let telemetry = Telemetry {
+        #[cfg(feature = "otlp")]
+        otlp: otlp.is_some(),
+    };
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter(verbosity)))
+        .with(otlp)
+        .try_init()?;
+    Ok(telemetry)
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::{Context, Result};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// A layer sending finished spans in batches to the collector at `endpoint`
+    pub fn layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, trace::Tracer>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])))
+            .install_batch(runtime::Tokio)
+            .with_context(|| format!("Failed to set up OTLP export to {}", endpoint))?;
+        Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+}
@@ -0,0 +1,53 @@
+use crate::error::{DnsError, Phase};
+use std::future::Future;
+use std::time::Duration;
+
+/// Per-phase limits applied to every upstream exchange
+///
+/// QUIC has no separate TCP connect, so DoH3 gives connection setup the
+/// combined `connect + handshake` budget. DoH relies on reqwest, which sets
+/// up connections inside the request, so it cannot split phases at all: the
+/// request is one exchange phase with the `connect + handshake + response`
+/// budget, and only reading the body is a response phase of its own.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    pub response: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(5),
+            response: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Budget for connection setup when TCP connect and handshake are not separable
+    pub fn setup(&self) -> Duration {
+        self.connect + self.handshake
+    }
+
+    /// Budget for a whole exchange when not even setup and query are separable
+    pub fn exchange(&self) -> Duration {
+        self.setup() + self.response
+    }
+}
+
+/// Run `fut`, failing with a phase-labelled timeout if it takes longer than `limit`
+pub async fn guard<F: Future>(
+    phase: Phase,
+    limit: Duration,
+    fut: F,
+) -> Result<F::Output, DnsError> {
+    tokio::time::timeout(limit, fut)
+        .await
+        .map_err(|_| DnsError::Timeout {
+            phase,
+            after: limit,
+        })
+}